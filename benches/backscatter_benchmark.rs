@@ -1,67 +1,56 @@
-use backscatter_rs::fitting::fitacf3::fitacf_v3::{fit_rawacf_record, Fitacf3Error};
+use backscatter_rs::fitting::fitacf3::fitacf_v3::{fit_rawacf_record, par_fit_rawacf_record};
 use backscatter_rs::utils::hdw::HdwInfo;
-use chrono::NaiveDateTime;
-use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use dmap::formats::{DmapRecord, FitacfRecord, RawacfRecord};
 
-use dmap;
 use rayon::prelude::*;
-use std::fs::File;
+use std::fs::{read, remove_file};
+
+const RAWACF_FILE: &str = "tests/test_files/test.rawacf";
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("Fitacf3", |b| b.iter(|| fitacf3()));
-    c.bench_function("Parallel Fitacf3", |b| b.iter(|| rayon_fitacf3()));
+    let bytes = read(RAWACF_FILE).expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(&bytes[..]).expect("Could not read records");
+    let hdw = HdwInfo::for_record(&rawacf[0]).expect("Unable to read hdw file");
+
+    c.bench_function("Read rawacf", |b| {
+        b.iter(|| {
+            RawacfRecord::read_records(black_box(&bytes[..])).expect("Could not read records")
+        })
+    });
+    c.bench_function("Write rawacf", |b| {
+        let outfile = std::env::temp_dir().join("backscatter_benchmark.rawacf");
+        let outfile = outfile
+            .to_str()
+            .expect("Temporary directory is not valid UTF-8");
+        b.iter(|| dmap::formats::to_file(outfile, &rawacf).expect("Unable to write to file"));
+        remove_file(outfile).expect("Unable to delete file");
+    });
+    c.bench_function("Fit record", |b| {
+        b.iter(|| fit_rawacf_record(black_box(&rawacf[0]), &hdw).expect("Could not fit record"))
+    });
+    c.bench_function("Parallel fit record", |b| {
+        b.iter(|| par_fit_rawacf_record(black_box(&rawacf[0]), &hdw).expect("Could not fit record"))
+    });
+    c.bench_function("Fitacf3", |b| b.iter(|| fitacf3(black_box(&rawacf), &hdw)));
+    c.bench_function("Parallel Fitacf3", |b| {
+        b.iter(|| rayon_fitacf3(black_box(&rawacf), &hdw))
+    });
 }
 
-fn fitacf3() {
-    let file =
-        File::open("tests/test_files/20210607.1801.00.cly.a.rawacf").expect("Test file not found");
-    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+fn fitacf3(rawacf: &[RawacfRecord], hdw: &HdwInfo) -> Vec<FitacfRecord> {
     let mut fitacf_records = vec![];
-
-    let rec = &rawacf[0];
-    let file_datetime = NaiveDateTime::parse_from_str(
-        format!(
-            "{:4}{:0>2}{:0>2} {:0>2}:{:0>2}:{:0>2}",
-            rec.year, rec.month, rec.day, rec.hour, rec.minute, rec.second
-        )
-        .as_str(),
-        "%Y%m%d %H:%M:%S",
-    )
-    .expect("Unable to interpret record timestamp");
-    let hdw = HdwInfo::new(rec.station_id, file_datetime).expect("Unable to read hdw file");
-    // fitacf_records.push(fit_rawacf_record(&rawacf[0]).expect("Could not fit rawacf record"));
     for rec in rawacf {
-        fitacf_records.push(fit_rawacf_record(&rec, &hdw).expect("Could not fit record"));
+        fitacf_records.push(fit_rawacf_record(rec, hdw).expect("Could not fit record"));
     }
-    dmap::formats::to_file("tests/test_files/temp.fitacf", &fitacf_records)
-        .expect("Unable to write to file");
+    fitacf_records
 }
 
-fn rayon_fitacf3() {
-    let file =
-        File::open("tests/test_files/20210607.1801.00.cly.a.rawacf").expect("Test file not found");
-    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
-    let fitacf_records: Vec<FitacfRecord>;
-
-    let rec = &rawacf[0];
-    let file_datetime = NaiveDateTime::parse_from_str(
-        format!(
-            "{:4}{:0>2}{:0>2} {:0>2}:{:0>2}:{:0>2}",
-            rec.year, rec.month, rec.day, rec.hour, rec.minute, rec.second
-        )
-        .as_str(),
-        "%Y%m%d %H:%M:%S",
-    )
-    .expect("Unable to interpret record timestamp");
-    let hdw = HdwInfo::new(rec.station_id, file_datetime).expect("Unable to read utils file");
-
-    fitacf_records = rawacf
+fn rayon_fitacf3(rawacf: &[RawacfRecord], hdw: &HdwInfo) -> Vec<FitacfRecord> {
+    rawacf
         .par_iter()
-        .map(|rec| fit_rawacf_record(&rec, &hdw).expect("Could not fit record"))
-        .collect();
-    dmap::formats::to_file("tests/test_files/temp.fitacf", &fitacf_records)
-        .expect("Unable to write to file");
+        .map(|rec| fit_rawacf_record(rec, hdw).expect("Could not fit record"))
+        .collect()
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,233 @@
+use crate::scan::radar_scan::RadarBeam;
+use crate::utils::hdw::HdwInfo;
+
+/// Equatorial radius of the Earth in km, as used by RST's `geodtgc`
+const EARTH_EQUATORIAL_RADIUS: f64 = 6378.16;
+/// Flattening of the Earth, as used by RST's `geodtgc`
+const EARTH_FLATTENING: f64 = 1.0 / 298.25;
+
+/// Virtual height model used to place the scatter of a range gate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VirtualHeight {
+    /// RST's standard model: the given height in km, lowered to E-region heights for near
+    /// ranges when the height is above 150 km, and scaled down towards the radar within 150 km
+    /// of it.
+    Standard(f64),
+    /// The range-dependent model of Chisham et al. (2008).
+    Chisham,
+}
+
+impl Default for VirtualHeight {
+    fn default() -> Self {
+        VirtualHeight::Standard(300.0)
+    }
+}
+
+impl VirtualHeight {
+    /// Virtual height in km of scatter at a slant range in km
+    pub fn height(&self, slant_range: f64) -> f64 {
+        match *self {
+            VirtualHeight::Standard(_) if slant_range < 150.0 => slant_range / 150.0 * 115.0,
+            VirtualHeight::Standard(height) => {
+                if height <= 150.0 {
+                    height
+                } else if slant_range <= 600.0 {
+                    115.0
+                } else if slant_range < 800.0 {
+                    (slant_range - 600.0) / 200.0 * (height - 115.0) + 115.0
+                } else {
+                    height
+                }
+            }
+            VirtualHeight::Chisham => {
+                let (a, b, c) = if slant_range < 115.0 {
+                    return slant_range / 115.0 * 112.0;
+                } else if slant_range < 787.5 {
+                    (108.974, 0.0191271, 6.68283e-5)
+                } else if slant_range <= 2137.5 {
+                    (384.416, -0.178640, 1.81405e-4)
+                } else {
+                    (1098.28, -0.354557, 9.39961e-5)
+                };
+                a + b * slant_range + c * slant_range * slant_range
+            }
+        }
+    }
+}
+
+/// Slant range in km to the centre of a range gate, as in RST's `slant_range`.
+/// `first_range` and `range_sep` are in km, and `rx_rise` is in microseconds.
+pub fn slant_range(first_range: i32, range_sep: i32, rx_rise: i32, range_gate: usize) -> f64 {
+    let lag_first_range = (first_range * 20 / 3) as f64;
+    let sample_sep = (range_sep * 20 / 3) as f64;
+    (lag_first_range - rx_rise as f64 + range_gate as f64 * sample_sep) * 0.15
+}
+
+/// Geocentric latitude and longitude in degrees, and the virtual height in km, of the centre
+/// of a range gate of a beam. Mirrors RST's `RPosGeo`, which also returns geocentric latitude.
+/// The hardware parameters are those of the beam's radar at the time of the beam, and give the
+/// receiver rise time of beams that do not record one. Returns `None` when the range is too
+/// short for the ray to reach the virtual height.
+pub fn cell_geolocation(
+    beam: &RadarBeam,
+    range_gate: usize,
+    hdw: &HdwInfo,
+    model: VirtualHeight,
+) -> Option<(f64, f64, f64)> {
+    let rx_rise = if beam.rx_rise_time == 0 {
+        hdw.rx_rise_time as i32
+    } else {
+        beam.rx_rise_time
+    };
+    let offset = hdw.max_num_beams as f64 / 2.0 - 0.5;
+    let psi = hdw.beam_separation as f64 * (beam.beam as f64 - offset) + hdw.boresight_shift as f64;
+    let range = slant_range(beam.first_range, beam.range_sep, rx_rise, range_gate);
+    let height = model.height(range);
+    let (lat, lon) = field_point(
+        hdw.latitude as f64,
+        hdw.longitude as f64,
+        psi,
+        hdw.boresight as f64,
+        height,
+        range,
+    )?;
+    Some((lat, lon, height))
+}
+
+/// Geocentric position of the point at slant range `range` along a beam at angle `psi` from
+/// the boresight, which lies at a height `height` above the Earth. Mirrors RST's `fldpnth`,
+/// but returns `None` rather than NaN when the height cannot be reached at that range.
+fn field_point(
+    geod_lat: f64,
+    geod_lon: f64,
+    psi: f64,
+    boresight: f64,
+    height: f64,
+    range: f64,
+) -> Option<(f64, f64)> {
+    let (site_rho, site_lat, site_lon, _) = geodetic_to_geocentric(geod_lat, geod_lon);
+    let mut earth_rho = site_rho;
+    loop {
+        let field_rho = earth_rho + height;
+        let elevation = ((field_rho * field_rho - site_rho * site_rho - range * range)
+            / (2.0 * site_rho * range))
+            .asin()
+            .to_degrees();
+        if elevation.is_nan() {
+            return None;
+        }
+        let (sin_psi, cos_psi) = psi.to_radians().sin_cos();
+        let sin_elv = elevation.to_radians().sin();
+        let tan_azimuth = if cos_psi * cos_psi - sin_elv * sin_elv < 0.0 {
+            1e32
+        } else {
+            (sin_psi * sin_psi / (cos_psi * cos_psi - sin_elv * sin_elv)).sqrt()
+        };
+        let azimuth = if psi > 0.0 {
+            tan_azimuth.atan().to_degrees()
+        } else {
+            -tan_azimuth.atan().to_degrees()
+        };
+        let (ray_azimuth, _) =
+            geodetic_to_geocentric_direction(geod_lat, geod_lon, azimuth + boresight, elevation);
+
+        let (rho, lat, lon) = ray_end(site_rho, site_lat, site_lon, ray_azimuth, elevation, range);
+        earth_rho = geocentric_earth_radius(lat);
+        if (rho - earth_rho - height).abs() <= 0.5 {
+            break Some((lat, lon));
+        }
+    }
+}
+
+/// Converts a geodetic position to geocentric radius, latitude and longitude, and returns the
+/// difference between geodetic and geocentric latitude. Mirrors RST's `geodtgc`.
+fn geodetic_to_geocentric(geod_lat: f64, geod_lon: f64) -> (f64, f64, f64, f64) {
+    let b = EARTH_EQUATORIAL_RADIUS * (1.0 - EARTH_FLATTENING);
+    let lat = ((b * b) / (EARTH_EQUATORIAL_RADIUS * EARTH_EQUATORIAL_RADIUS)
+        * geod_lat.to_radians().tan())
+    .atan()
+    .to_degrees();
+    let lon = if geod_lon > 180.0 {
+        geod_lon - 360.0
+    } else {
+        geod_lon
+    };
+    (geocentric_earth_radius(lat), lat, lon, geod_lat - lat)
+}
+
+/// Radius of the Earth in km at a geocentric latitude
+fn geocentric_earth_radius(lat: f64) -> f64 {
+    let b = EARTH_EQUATORIAL_RADIUS * (1.0 - EARTH_FLATTENING);
+    let e2 = (EARTH_EQUATORIAL_RADIUS * EARTH_EQUATORIAL_RADIUS) / (b * b) - 1.0;
+    let sin_lat = lat.to_radians().sin();
+    EARTH_EQUATORIAL_RADIUS / (1.0 + e2 * sin_lat * sin_lat).sqrt()
+}
+
+/// Converts a direction given as azimuth and elevation in the local geodetic frame to the local
+/// geocentric frame. Mirrors RST's `geocnvrt`.
+fn geodetic_to_geocentric_direction(
+    geod_lat: f64,
+    geod_lon: f64,
+    azimuth: f64,
+    elevation: f64,
+) -> (f64, f64) {
+    let (sin_azm, cos_azm) = azimuth.to_radians().sin_cos();
+    let (sin_elv, cos_elv) = elevation.to_radians().sin_cos();
+    let (kx, ky, kz) = (cos_elv * sin_azm, cos_elv * cos_azm, sin_elv);
+
+    let (_, _, _, delta) = geodetic_to_geocentric(geod_lat, geod_lon);
+    let (sin_del, cos_del) = delta.to_radians().sin_cos();
+    let ky_rot = ky * cos_del + kz * sin_del;
+    let kz_rot = -ky * sin_del + kz * cos_del;
+    (
+        kx.atan2(ky_rot).to_degrees(),
+        (kz_rot / (kx * kx + ky_rot * ky_rot).sqrt())
+            .atan()
+            .to_degrees(),
+    )
+}
+
+/// Geocentric radius, latitude and longitude of the end of a ray of length `range` leaving a
+/// point in the given direction. Mirrors RST's `fldpnt`.
+fn ray_end(
+    rho: f64,
+    lat: f64,
+    lon: f64,
+    azimuth: f64,
+    elevation: f64,
+    range: f64,
+) -> (f64, f64, f64) {
+    let (sin_theta, cos_theta) = (90.0 - lat).to_radians().sin_cos();
+    let (sin_lon, cos_lon) = lon.to_radians().sin_cos();
+    let (sin_azm, cos_azm) = azimuth.to_radians().sin_cos();
+    let (sin_elv, cos_elv) = elevation.to_radians().sin_cos();
+
+    let (rx, ry, rz) = (
+        rho * sin_theta * cos_lon,
+        rho * sin_theta * sin_lon,
+        rho * cos_theta,
+    );
+
+    // Ray in the local south-east-up frame, rotated into the global frame
+    let (sx, sy, sz) = (
+        -range * cos_elv * cos_azm,
+        range * cos_elv * sin_azm,
+        range * sin_elv,
+    );
+    let (tx, ty, tz) = (
+        cos_theta * sx + sin_theta * sz,
+        sy,
+        -sin_theta * sx + cos_theta * sz,
+    );
+    let (sx, sy, sz) = (cos_lon * tx - sin_lon * ty, sin_lon * tx + cos_lon * ty, tz);
+
+    let (tx, ty, tz) = (rx + sx, ry + sy, rz + sz);
+    let field_rho = (tx * tx + ty * ty + tz * tz).sqrt();
+    let field_lat = 90.0 - (tz / field_rho).acos().to_degrees();
+    let field_lon = if tx == 0.0 && ty == 0.0 {
+        0.0
+    } else {
+        ty.atan2(tx).to_degrees()
+    };
+    (field_rho, field_lat, field_lon)
+}
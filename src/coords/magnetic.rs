@@ -0,0 +1,32 @@
+use crate::error::BackscatterError;
+
+type Result<T> = std::result::Result<T, BackscatterError>;
+
+/// Conversion from geographic to magnetic coordinates, as needed for gridding and mapping.
+/// Implementations such as AACGM-v2 are supplied by the caller, so the coefficient sets and
+/// evaluation method can be swapped without changing the code that depends on them.
+pub trait MagneticCoord {
+    /// Converts a geographic latitude and longitude in degrees, at a height in km, to a
+    /// magnetic latitude and longitude in degrees for the given year.
+    fn geo_to_magnetic(&self, lat: f64, lon: f64, height: f64, year: i32) -> Result<(f64, f64)>;
+}
+
+/// Converts geographic to AACGM coordinates using the given implementation, mirroring the
+/// signature of RST's `AACGMConvert`.
+pub fn geo_to_aacgm<C: MagneticCoord>(
+    coords: &C,
+    lat: f64,
+    lon: f64,
+    height: f64,
+    year: i32,
+) -> Result<(f64, f64)> {
+    if !(-90.0..=90.0).contains(&lat) {
+        Err(BackscatterError::new(
+            "Latitude must be within [-90, 90] degrees",
+        ))?
+    }
+    if height < 0.0 {
+        Err(BackscatterError::new("Height must be non-negative"))?
+    }
+    coords.geo_to_magnetic(lat, lon, height, year)
+}
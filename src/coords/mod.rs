@@ -0,0 +1,2 @@
+pub mod geolocation;
+pub mod magnetic;
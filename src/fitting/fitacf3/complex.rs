@@ -0,0 +1,34 @@
+use dmap::formats::RawacfRecord;
+use num_complex::Complex;
+
+/// The ACF of a range as complex numbers, one per lag.
+/// `range_idx` is the index of the range in the record's range list, as in `RangeNode`.
+///
+/// # Panics
+///
+/// If `range_idx` is not an index into the record's range list.
+pub fn acf_complex(rec: &RawacfRecord, range_idx: usize) -> Vec<Complex<f64>> {
+    interleaved_to_complex(&rec.acfs.data, range_idx, rec.num_lags as usize)
+}
+
+/// The XCF of a range as complex numbers, one per lag, or `None` if the record has no xcfs.
+///
+/// # Panics
+///
+/// If `range_idx` is not an index into the record's range list.
+pub fn xcf_complex(rec: &RawacfRecord, range_idx: usize) -> Option<Vec<Complex<f64>>> {
+    rec.xcfs
+        .as_ref()
+        .map(|xcfs| interleaved_to_complex(&xcfs.data, range_idx, rec.num_lags as usize))
+}
+
+/// acfd and xcfd are stored as [num_ranges, num_lags, 2] in memory, with 2 corresponding to
+/// real, imag. Panics if the range lies outside of `data`.
+fn interleaved_to_complex(data: &[f32], range_idx: usize, num_lags: usize) -> Vec<Complex<f64>> {
+    let start_idx = range_idx * 2 * num_lags;
+    let end_idx = start_idx + 2 * num_lags;
+    data[start_idx..end_idx]
+        .chunks_exact(2)
+        .map(|x| Complex::new(x[0] as f64, x[1] as f64))
+        .collect()
+}
@@ -1,4 +1,4 @@
-use crate::fitting::fitacf3::fitacf_v3::Fitacf3Error;
+use crate::fitting::fitacf3::fitacf_v3::{Fitacf3Error, Fitacf3Settings};
 use crate::fitting::fitacf3::fitstruct::RangeNode;
 use crate::utils::hdw::HdwInfo;
 use dmap::formats::{FitacfRecord, RawacfRecord};
@@ -10,12 +10,65 @@ pub const FITACF_REVISION_MAJOR: i32 = 3;
 pub const FITACF_REVISION_MINOR: i32 = 0;
 pub const V_MAX: f32 = 30.0;
 pub const W_MAX: f32 = 90.0;
+/// Speed of light in m/s, in the single precision of the fitacf output
+pub const SPEED_OF_LIGHT: f32 = 299792458.0;
+
+/// Line-of-sight velocity in m/s from the slope of the ACF phase fit in rad/s, for a transmit
+/// frequency in kHz. Positive slopes give positive velocities, as in fitacf3; the `velocity_sign`
+/// of the radar's hdw file is applied on top of this. Computed in single precision, in the same
+/// order as RST, so that fitted velocities match RST exactly.
+pub fn slope_to_velocity(slope: f32, tfreq_khz: f32) -> f32 {
+    slope * (SPEED_OF_LIGHT / (4.0 * PI_f32 * tfreq_khz * 1000.0))
+}
+
+/// Spectral width in m/s of the exponential (lambda) power model, from the slope of the linear
+/// fit to ln(power) in 1/s, for a transmit frequency in kHz. The slope is negative for a decaying
+/// ACF, so only its magnitude is used. Also converts the slope error to a width error.
+pub fn decay_to_width(lambda_slope: f32, tfreq_khz: f32) -> f32 {
+    lambda_slope.abs() * (SPEED_OF_LIGHT / (2.0 * PI_f32 * tfreq_khz * 1000.0))
+}
+
+/// Spectral width in m/s of the Gaussian (sigma) power model, from the slope of the quadratic
+/// fit to ln(power) in 1/s^2, for a transmit frequency in kHz.
+pub fn sigma_decay_to_width(sigma_slope: f32, tfreq_khz: f32) -> f32 {
+    sigma_slope.abs().sqrt()
+        * (SPEED_OF_LIGHT * (2.0_f32).ln().sqrt() / (PI_f32 * tfreq_khz * 1000.0))
+}
+
+/// Position of the interferometer array relative to the main array, and the signal delay between
+/// them, as used for elevation angles. Mirrors the interferometer fields of the hdw files.
+///
+/// The offsets are in metres, with y positive when the interferometer is in front of the main
+/// array. Some older radars have the interferometer behind the main array (negative y), which
+/// flips the sign of the measured phase difference and of the elevation correction for any
+/// vertical offset. `tdiff` is the extra delay in microseconds of the interferometer signal path,
+/// and is removed from the phase difference as a cable offset.
+#[derive(Debug, Clone)]
+pub struct InterferometerGeometry {
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub offset_z: f32,
+    pub tdiff: f32,
+}
+
+impl InterferometerGeometry {
+    /// Geometry from the hdw file of the radar, using the tdiff of channel A.
+    pub fn from_hdw(hdw: &HdwInfo) -> InterferometerGeometry {
+        InterferometerGeometry {
+            offset_x: hdw.intf_offset_x,
+            offset_y: hdw.intf_offset_y,
+            offset_z: hdw.intf_offset_z,
+            tdiff: hdw.tdiff_a,
+        }
+    }
+}
 
 pub fn determinations(
     rec: &RawacfRecord,
     ranges: Vec<RangeNode>,
     noise_power: f32,
     hdw: &HdwInfo,
+    settings: &Fitacf3Settings,
 ) -> Result<FitacfRecord, Fitacf3Error> {
     let range_list: Vec<i16> = ranges.iter().map(|r| r.range_num as i16).collect();
     let lag_0_power_db: Vec<f32> = rec
@@ -183,74 +236,80 @@ pub fn determinations(
                     / (10.0_f32).ln()
             })
             .collect();
-        let velocity_conversion: f32 =
-            299792458.0 * hdw.velocity_sign / (4.0 * PI_f32 * rec.tx_freq as f32 * 1000.0);
+        // The errors are the standard deviations of the fitted slopes, which convert the same way
+        let tfreq = rec.tx_freq as f32;
         let velocity: Vec<f32> = ranges
             .iter()
             .map(|r| {
-                (r.phase_fit
+                let slope = r
+                    .phase_fit
                     .as_ref()
                     .expect("Unable to make fitacf without fitted velocity")
-                    .slope as f32)
-                    * velocity_conversion
+                    .slope as f32;
+                slope_to_velocity(slope, tfreq) * hdw.velocity_sign
             })
             .collect();
         let velocity_error: Vec<f32> = ranges
             .iter()
             .map(|r| {
-                (r.phase_fit
+                let std_dev = (r
+                    .phase_fit
                     .as_ref()
                     .expect("Unable to make fitacf without fitted velocity")
                     .variance_slope as f32)
-                    .sqrt()
-                    * velocity_conversion
+                    .sqrt();
+                slope_to_velocity(std_dev, tfreq) * hdw.velocity_sign
             })
             .collect();
-        let width_conversion: f32 =
-            299792458.0 * 2.0 / (4.0 * PI_f32 * rec.tx_freq as f32 * 1000.0);
         let spectral_width_linear: Vec<f32> = ranges
             .iter()
             .map(|r| {
-                (r.lin_pwr_fit
+                let slope = r
+                    .lin_pwr_fit
                     .as_ref()
                     .expect("Unable to make fitacf spectral width without fitted power")
-                    .slope as f32)
-                    .abs()
-                    * width_conversion
+                    .slope as f32;
+                decay_to_width(slope, tfreq)
             })
             .collect();
         let spectral_width_linear_error: Vec<f32> = ranges
             .iter()
             .map(|r| {
-                (r.lin_pwr_fit_err
+                let std_dev = (r
+                    .lin_pwr_fit_err
                     .as_ref()
                     .expect("Unable to make fitacf spectral width error without fitted power error")
                     .variance_slope as f32)
-                    .sqrt()
-                    * width_conversion
+                    .sqrt();
+                decay_to_width(std_dev, tfreq)
             })
             .collect();
-        let quadratic_width_conversion: f32 =
-            299792458.0 * (2.0_f32).ln().sqrt() / (PI_f32 * rec.tx_freq as f32 * 1000.0);
         let spectral_width_quadratic: Vec<f32> = ranges
             .iter()
             .map(|r| {
-                (r.quad_pwr_fit
+                let slope = r
+                    .quad_pwr_fit
                     .as_ref()
                     .expect("Unable to make fitacf quadratic spectral width without fitted power")
-                    .slope as f32)
-                    .abs()
-                    .sqrt()
-                    * quadratic_width_conversion
+                    .slope as f32;
+                sigma_decay_to_width(slope, tfreq)
             })
             .collect();
         let spectral_width_quadratic_error: Vec<f32> = ranges
             .iter()
             .map(|r| {
-                (r.quad_pwr_fit_err.as_ref().expect("Unable to make fitacf quadratic spectral width error without fitted power error")
-                    .variance_slope as f32).sqrt() * quadratic_width_conversion /
-                    ((r.quad_pwr_fit.as_ref().expect("Unable to make fitacf quadratic spectral width error without fitted power error")
-                        .slope as f32).abs().sqrt() * 2.0)
+                let variance = r
+                    .quad_pwr_fit_err
+                    .as_ref()
+                    .expect("Unable to make fitacf quadratic spectral width error without fitted power error")
+                    .variance_slope as f32;
+                let slope = r
+                    .quad_pwr_fit
+                    .as_ref()
+                    .expect("Unable to make fitacf quadratic spectral width error without fitted power error")
+                    .slope as f32;
+                // Error propagated through the square root of the slope
+                variance.sqrt() * sigma_decay_to_width(1.0, tfreq) / (slope.abs().sqrt() * 2.0)
             })
             .collect();
         let std_dev_linear: Vec<f32> = ranges
@@ -280,52 +339,23 @@ pub fn determinations(
                     .chi_squared as f32
             })
             .collect();
+        let groundscatter = settings.groundscatter_params();
         let groundscatter_flag: Vec<i8> = zip(velocity.iter(), spectral_width_linear.iter())
-            .map(|(v, w)| (v.abs() - (V_MAX - w * (V_MAX / W_MAX)) < 1.0) as i8)
+            .map(|(&v, &w)| groundscatter.is_groundscatter(v, w) as i8)
             .collect();
-        let xcfs = &rec
+        // Records without xcfs, from radars without an interferometer array, have no elevation
+        let xcf = rec
             .xcfs
             .as_ref()
-            .expect("Unable to make fitacf xcf_phi0")
-            .data;
-        let xcf_phi0: Vec<f32> = ranges
-            .iter()
-            .map(|r| {
-                xcfs[r.range_idx * rec.num_lags as usize * 2 + 1]
-                    .atan2(xcfs[r.range_idx * rec.num_lags as usize * 2])
-                    * hdw.phase_sign
-            })
-            .collect();
-        let xcf_phi0_err: Vec<f32> = ranges
-            .iter()
-            .map(|r| {
-                (r.elev_fit
-                    .as_ref()
-                    .expect("Unable to make fitacf xcf_phi0_err")
-                    .variance_intercept as f32)
-                    .sqrt()
-            })
-            .collect();
-        let xcf_phi_std_dev: Vec<f32> = ranges
-            .iter()
-            .map(|r| {
-                r.elev_fit
-                    .as_ref()
-                    .expect("Unable to make fitacf xcf_phi_std_dev")
-                    .chi_squared as f32
-            })
-            .collect();
-        let (elevation_low, elevation_normal, elevation_high) =
-            calculate_elevation(&ranges, rec, &xcf_phi0, hdw);
-
-        let float_zeros = DmapVec {
+            .map(|xcfs| xcf_determinations(&ranges, rec, &xcfs.data, hdw, settings));
+        let float_zeros = xcf.as_ref().map(|_| DmapVec {
             data: quality_flag.iter().map(|_| 0.0_f32).collect(),
             dimensions: vec![quality_flag.len() as i32],
-        };
-        let i8_zeros = DmapVec {
+        });
+        let i8_zeros = xcf.as_ref().map(|_| DmapVec {
             data: quality_flag.iter().map(|_| 0_i8).collect(),
             dimensions: vec![quality_flag.len() as i32],
-        };
+        });
 
         Ok(FitacfRecord {
             radar_revision_major: rec.radar_revision_major,
@@ -401,28 +431,34 @@ pub fn determinations(
             lambda_std_dev: convert_to_dmapvec(std_dev_linear),
             sigma_std_dev: convert_to_dmapvec(std_dev_quadratic),
             phi_std_dev: convert_to_dmapvec(std_dev_phi),
-            xcf_quality_flag: Some(i8_zeros.clone()),
-            xcf_ground_flag: Some(i8_zeros),
-            lambda_xcf_power: Some(float_zeros.clone()),
-            lambda_xcf_power_error: Some(float_zeros.clone()),
-            sigma_xcf_power: Some(float_zeros.clone()),
-            sigma_xcf_power_error: Some(float_zeros.clone()),
-            xcf_velocity: Some(float_zeros.clone()),
-            xcf_velocity_error: Some(float_zeros.clone()),
-            lambda_xcf_spectral_width: Some(float_zeros.clone()),
-            lambda_xcf_spectral_width_error: Some(float_zeros.clone()),
-            sigma_xcf_spectral_width: Some(float_zeros.clone()),
-            sigma_xcf_spectral_width_error: Some(float_zeros.clone()),
-            lag_zero_phi: Some(convert_to_dmapvec(xcf_phi0)),
-            lag_zero_phi_error: Some(convert_to_dmapvec(xcf_phi0_err)),
-            elevation: Some(convert_to_dmapvec(elevation_normal)),
+            xcf_quality_flag: i8_zeros.clone(),
+            xcf_ground_flag: i8_zeros,
+            lambda_xcf_power: float_zeros.clone(),
+            lambda_xcf_power_error: float_zeros.clone(),
+            sigma_xcf_power: float_zeros.clone(),
+            sigma_xcf_power_error: float_zeros.clone(),
+            xcf_velocity: float_zeros.clone(),
+            xcf_velocity_error: float_zeros.clone(),
+            lambda_xcf_spectral_width: float_zeros.clone(),
+            lambda_xcf_spectral_width_error: float_zeros.clone(),
+            sigma_xcf_spectral_width: float_zeros.clone(),
+            sigma_xcf_spectral_width_error: float_zeros.clone(),
+            lag_zero_phi: xcf.as_ref().map(|x| convert_to_dmapvec(x.phi0.clone())),
+            lag_zero_phi_error: xcf.as_ref().map(|x| convert_to_dmapvec(x.phi0_err.clone())),
+            elevation: xcf
+                .as_ref()
+                .map(|x| convert_to_dmapvec(x.elevation_normal.clone())),
             elevation_fitted: None,
             elevation_error: None,
-            elevation_low: Some(convert_to_dmapvec(elevation_low)),
-            elevation_high: Some(convert_to_dmapvec(elevation_high)),
-            lambda_xcf_std_dev: Some(float_zeros.clone()),
-            sigma_xcf_std_dev: Some(float_zeros),
-            phi_xcf_std_dev: Some(convert_to_dmapvec(xcf_phi_std_dev)),
+            elevation_low: xcf
+                .as_ref()
+                .map(|x| convert_to_dmapvec(x.elevation_low.clone())),
+            elevation_high: xcf
+                .as_ref()
+                .map(|x| convert_to_dmapvec(x.elevation_high.clone())),
+            lambda_xcf_std_dev: float_zeros.clone(),
+            sigma_xcf_std_dev: float_zeros,
+            phi_xcf_std_dev: xcf.map(|x| convert_to_dmapvec(x.phi_std_dev)),
         })
     }
 }
@@ -434,15 +470,77 @@ fn convert_to_dmapvec<T: InDmap>(vals: Vec<T>) -> DmapVec<T> {
     }
 }
 
+/// Lag-zero XCF phase and elevation of each range, for records with xcfs
+struct XcfDeterminations {
+    phi0: Vec<f32>,
+    phi0_err: Vec<f32>,
+    phi_std_dev: Vec<f32>,
+    elevation_low: Vec<f32>,
+    elevation_normal: Vec<f32>,
+    elevation_high: Vec<f32>,
+}
+
+fn xcf_determinations(
+    ranges: &[RangeNode],
+    rec: &RawacfRecord,
+    xcfs: &[f32],
+    hdw: &HdwInfo,
+    settings: &Fitacf3Settings,
+) -> XcfDeterminations {
+    let xcf_phi0: Vec<f32> = ranges
+        .iter()
+        .map(|r| {
+            xcfs[r.range_idx * rec.num_lags as usize * 2 + 1]
+                .atan2(xcfs[r.range_idx * rec.num_lags as usize * 2])
+                * hdw.phase_sign
+        })
+        .collect();
+    let xcf_phi0_err: Vec<f32> = ranges
+        .iter()
+        .map(|r| {
+            (r.elev_fit
+                .as_ref()
+                .expect("Unable to make fitacf xcf_phi0_err")
+                .variance_intercept as f32)
+                .sqrt()
+        })
+        .collect();
+    let xcf_phi_std_dev: Vec<f32> = ranges
+        .iter()
+        .map(|r| {
+            r.elev_fit
+                .as_ref()
+                .expect("Unable to make fitacf xcf_phi_std_dev")
+                .chi_squared as f32
+        })
+        .collect();
+    let geometry = settings
+        .interferometer
+        .clone()
+        .unwrap_or_else(|| InterferometerGeometry::from_hdw(hdw));
+    let (elevation_low, elevation_normal, elevation_high) =
+        calculate_elevation(ranges, rec, &xcf_phi0, hdw, &geometry);
+
+    XcfDeterminations {
+        phi0: xcf_phi0,
+        phi0_err: xcf_phi0_err,
+        phi_std_dev: xcf_phi_std_dev,
+        elevation_low,
+        elevation_normal,
+        elevation_high,
+    }
+}
+
 fn calculate_elevation(
     ranges: &[RangeNode],
     rec: &RawacfRecord,
     xcf_phi0: &[f32],
     hdw: &HdwInfo,
+    geometry: &InterferometerGeometry,
 ) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
-    let x = hdw.intf_offset_x;
-    let y = hdw.intf_offset_y;
-    let z = hdw.intf_offset_z;
+    let x = geometry.offset_x;
+    let y = geometry.offset_y;
+    let z = geometry.offset_z;
 
     let array_separation: f32 = (x * x + y * y + z * z).sqrt();
     let mut elevation_corr = (z / array_separation).asin();
@@ -456,8 +554,8 @@ fn calculate_elevation(
     let azimuth_offset = hdw.max_num_beams as f32 / 2.0 - 0.5;
     let phi_0 =
         (hdw.beam_separation * (rec.beam_num as f32 - azimuth_offset) * PI_f32 / 180.0).cos();
-    let wave_num = 2.0 * PI_f32 * rec.tx_freq as f32 * 1000.0 / 299792458.0;
-    let cable_offset = -2.0 * PI_f32 * rec.tx_freq as f32 * 1000.0 * hdw.tdiff_a * 1.0e-6;
+    let wave_num = 2.0 * PI_f32 * rec.tx_freq as f32 * 1000.0 / SPEED_OF_LIGHT;
+    let cable_offset = -2.0 * PI_f32 * rec.tx_freq as f32 * 1000.0 * geometry.tdiff * 1.0e-6;
     let phase_diff_max = phi_sign * wave_num * array_separation * phi_0 + cable_offset;
     let mut psi: Vec<f32> = ranges
         .iter()
@@ -1,9 +1,8 @@
-use crate::fitting::fitacf3::fitacf_v3::{
-    Fitacf3Error, ALPHA_CUTOFF, FLUCTUATION_CUTOFF_COEFFICIENT, MIN_LAGS,
-};
+use crate::fitting::fitacf3::fitacf_v3::{Fitacf3Error, Fitacf3Settings, LOG_TARGET};
 use crate::fitting::fitacf3::fitstruct::{LagNode, RangeNode};
 use dmap::formats::RawacfRecord;
 use is_close::is_close;
+use log::debug;
 
 /// passing
 pub fn mark_bad_samples(rec: &RawacfRecord) -> Vec<i32> {
@@ -57,26 +56,76 @@ pub fn mark_bad_samples(rec: &RawacfRecord) -> Vec<i32> {
     bad_samples
 }
 
+/// Whether the lag-zero power of a range is at least `min_snr_db` above the noise level.
+/// Without a positive noise level there is no SNR to compare, so every range with power passes.
+pub fn range_passes_snr(rec: &RawacfRecord, range_num: i32, noise: f64, min_snr_db: f64) -> bool {
+    let power = match rec.lag_zero_power.data.get(range_num as usize) {
+        Some(&p) if range_num >= 0 => p as f64,
+        _ => return false,
+    };
+    power > 0.0 && (noise <= 0.0 || 10.0 * (power / noise).log10() >= min_snr_db)
+}
+
+/// Drops ranges too weak to fit, so they are left without scatter in the output. Since
+/// `filter_bad_acfs` already drops ranges within twice (3 dB of) the noise, this only removes
+/// further ranges when `min_snr_db` is above 3 dB.
+pub fn filter_low_snr_ranges(
+    rec: &RawacfRecord,
+    ranges: &mut Vec<RangeNode>,
+    noise_power: f32,
+    min_snr_db: f64,
+) {
+    if rec.num_averages <= 0 {
+        return;
+    }
+    ranges.retain(|range| {
+        let passes = range_passes_snr(rec, range.range_num as i32, noise_power as f64, min_snr_db);
+        if !passes {
+            debug!(
+                target: LOG_TARGET,
+                "Range {}: skipping, SNR below {} dB", range.range_num, min_snr_db
+            );
+        }
+        passes
+    });
+}
+
+/// Indices of the lags which are missing at a range, since one of their samples falls within
+/// a transmit pulse. `bad_samples` are the blanked samples from `mark_bad_samples`.
+pub fn blanked_lags(lags: &[LagNode], bad_samples: &[i32], range_num: i32) -> Vec<usize> {
+    lags.iter()
+        .enumerate()
+        .filter(|(_, lag)| {
+            bad_samples.contains(&(lag.sample_base_1 + range_num))
+                || bad_samples.contains(&(lag.sample_base_2 + range_num))
+        })
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
 /// passing
 pub fn filter_tx_overlapped_lags(
     rec: &RawacfRecord,
-    lags: Vec<LagNode>,
+    lags: &[LagNode],
     ranges: &mut Vec<RangeNode>,
 ) {
     let bad_samples = mark_bad_samples(rec);
     for range_node in ranges {
-        let mut bad_indices = vec![];
-        for (idx, lag) in lags.iter().enumerate() {
-            let sample_1 = lag.sample_base_1 + range_node.range_num as i32;
-            let sample_2 = lag.sample_base_2 + range_node.range_num as i32;
-            if bad_samples.contains(&sample_1) || bad_samples.contains(&sample_2) {
-                bad_indices.push(idx);
-            }
+        let bad_indices = blanked_lags(lags, &bad_samples, range_node.range_num as i32);
+        if !bad_indices.is_empty() {
+            debug!(
+                target: LOG_TARGET,
+                "Range {}: skipping {} lags overlapped by transmit pulses",
+                range_node.range_num,
+                bad_indices.len()
+            );
         }
         for i in bad_indices.iter().rev() {
             range_node.powers.remove(*i);
             range_node.phases.remove(*i);
-            range_node.elev.remove(*i);
+            if let Some(elev) = range_node.elev.as_mut() {
+                elev.remove(*i);
+            }
             range_node.power_alpha_2.remove(*i);
             range_node.phase_alpha_2.remove(*i);
         }
@@ -92,6 +141,14 @@ pub fn filter_infinite_lags(ranges: &mut Vec<RangeNode>) {
                 infinite_indices.push(i);
             }
         }
+        if !infinite_indices.is_empty() {
+            debug!(
+                target: LOG_TARGET,
+                "Range {}: skipping {} lags with non-finite power",
+                range.range_num,
+                infinite_indices.len()
+            );
+        }
         for i in infinite_indices.iter().rev() {
             range.powers.remove(*i);
             range.power_alpha_2.remove(*i);
@@ -100,7 +157,11 @@ pub fn filter_infinite_lags(ranges: &mut Vec<RangeNode>) {
 }
 
 /// passing
-pub fn filter_low_power_lags(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>) {
+pub fn filter_low_power_lags(
+    rec: &RawacfRecord,
+    ranges: &mut Vec<RangeNode>,
+    settings: &Fitacf3Settings,
+) {
     if rec.num_averages <= 0 {
         return;
     }
@@ -109,7 +170,7 @@ pub fn filter_low_power_lags(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>) {
         if range.powers.ln_power.is_empty() {
             continue;
         }
-        let log_sigma_fluc = (FLUCTUATION_CUTOFF_COEFFICIENT as f32
+        let log_sigma_fluc = (settings.fluctuation_cutoff_coefficient
             * rec.lag_zero_power.data[range_num]
             / ((2 * rec.num_averages) as f32).sqrt())
         .ln();
@@ -122,7 +183,7 @@ pub fn filter_low_power_lags(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>) {
             } else {
                 let log_power = range.powers.ln_power[idx];
                 let alpha_2 = range.power_alpha_2[idx];
-                if ((1_f64 / alpha_2.sqrt()) <= ALPHA_CUTOFF as f64)
+                if ((1_f64 / alpha_2.sqrt()) <= settings.alpha_cutoff as f64)
                     && ((log_power < log_sigma_fluc as f64)
                         || is_close!(log_power, log_sigma_fluc as f64))
                 {
@@ -131,6 +192,14 @@ pub fn filter_low_power_lags(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>) {
                 }
             }
         }
+        if !bad_indices.is_empty() {
+            debug!(
+                target: LOG_TARGET,
+                "Range {}: skipping {} lags below the fluctuation level",
+                range_num,
+                bad_indices.len()
+            );
+        }
         for i in bad_indices.iter().rev() {
             range.powers.remove(*i);
             // range.phases.remove(*i);
@@ -141,7 +210,12 @@ pub fn filter_low_power_lags(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>) {
 }
 
 /// passing
-pub fn filter_bad_acfs(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>, noise_power: f32) {
+pub fn filter_bad_acfs(
+    rec: &RawacfRecord,
+    ranges: &mut Vec<RangeNode>,
+    noise_power: f32,
+    settings: &Fitacf3Settings,
+) {
     if rec.num_averages <= 0 {
         return;
     }
@@ -151,7 +225,7 @@ pub fn filter_bad_acfs(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>, noise_po
         let range_num = range.range_num as usize;
         let power = rec.lag_zero_power.data[range_num];
         let num_powers = range.powers.ln_power.len();
-        if (power <= cutoff_power) || (num_powers < MIN_LAGS as usize) {
+        if (power <= cutoff_power) || (num_powers < settings.min_lags as usize) {
             bad_indices.push(idx);
         } else {
             let power_value = range.powers.ln_power[0];
@@ -167,6 +241,11 @@ pub fn filter_bad_acfs(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>, noise_po
         }
     }
     for idx in bad_indices.iter().rev() {
+        debug!(
+            target: LOG_TARGET,
+            "Range {}: dropping ACF below noise cutoff or with too few lags",
+            ranges[*idx].range_num
+        );
         ranges.remove(*idx);
     }
 }
@@ -1,14 +1,21 @@
-use crate::fitting::fitacf3::fitstruct::{LagNode, RangeNode};
+use crate::fitting::fitacf3::fitstruct::{samples_per_increment, LagNode, RangeNode};
 
-use crate::fitting::fitacf3::determinations::determinations;
+use crate::fitting::fitacf3::determinations::{
+    determinations, InterferometerGeometry, V_MAX, W_MAX,
+};
 use crate::fitting::fitacf3::filtering;
 use crate::fitting::fitacf3::fitting;
+use crate::scan::groundscatter::GroundscatterParams;
 use crate::utils::hdw::HdwInfo;
-use dmap::formats::{FitacfRecord, RawacfRecord};
+use dmap::formats::{to_file, DmapRecord, FitacfRecord, RawacfRecord};
+use log::{debug, warn};
+use rayon::prelude::*;
 use std::error::Error;
 use std::f64::consts::PI;
 use std::fmt;
 use std::fmt::Display;
+use std::fs::File;
+use std::path::Path;
 
 type Result<T> = std::result::Result<T, Fitacf3Error>;
 
@@ -16,6 +23,68 @@ pub const FLUCTUATION_CUTOFF_COEFFICIENT: f32 = 2.0;
 pub const ALPHA_CUTOFF: f32 = 2.0;
 pub const ACF_SNR_CUTOFF: f64 = 1.0;
 pub const MIN_LAGS: i16 = 3;
+pub const MIN_SNR_DB: f64 = 1.0;
+
+/// Target of all log messages emitted while fitting
+pub const LOG_TARGET: &str = "backscatter::fitacf3";
+
+/// Thresholds of the fitacf3 pipeline. The defaults match RST's fitacf3 and reproduce its output.
+/// The settings are plain data, so a single instance can be shared by reference between threads.
+#[derive(Debug, Clone)]
+pub struct Fitacf3Settings {
+    /// Number of lowest nonzero lag-zero powers averaged for the noise estimate, as in RST's
+    /// `ACF_cutoff_pwr`. Must be at least 1 and less than the number of ranges of each record,
+    /// otherwise fitting the record fails.
+    pub noise_count: usize,
+    /// Noise estimates below this fall back to the search noise, RST's `ACF_SNR_CUTOFF`
+    pub acf_snr_cutoff: f64,
+    /// Minimum lag-zero power in dB above the noise for a range to be fit. This gate is not part
+    /// of RST's fitacf3, and the default of 1 dB is below the 3 dB (twice the noise) that
+    /// `filter_bad_acfs` already requires, so by default it removes nothing.
+    pub min_snr_db: f64,
+    /// Minimum number of good lags for an ACF to be fit, RST's `MIN_LAGS`
+    pub min_lags: i16,
+    /// Scale of the fluctuation level below which lags are dropped, RST's
+    /// `FLUCTUATION_CUTOFF_COEFF`
+    pub fluctuation_cutoff_coefficient: f32,
+    /// Cutoff on 1/alpha for dropping lags below the fluctuation level, RST's `ALPHA_CUTOFF`
+    pub alpha_cutoff: f32,
+    /// Groundscatter velocity limit in m/s at zero spectral width, `V_max` in RST's
+    /// `Determinations`
+    pub gs_velocity: f32,
+    /// Spectral width in m/s at which the groundscatter velocity limit reaches zero, `W_max` in
+    /// RST's `Determinations`
+    pub gs_width: f32,
+    /// Interferometer geometry overriding the one in the hdw file, e.g. for radars whose hdw
+    /// file has the wrong sign for an interferometer behind the main array
+    pub interferometer: Option<InterferometerGeometry>,
+}
+
+impl Default for Fitacf3Settings {
+    fn default() -> Self {
+        Fitacf3Settings {
+            noise_count: 10,
+            acf_snr_cutoff: ACF_SNR_CUTOFF,
+            min_snr_db: MIN_SNR_DB,
+            min_lags: MIN_LAGS,
+            fluctuation_cutoff_coefficient: FLUCTUATION_CUTOFF_COEFFICIENT,
+            alpha_cutoff: ALPHA_CUTOFF,
+            gs_velocity: V_MAX,
+            gs_width: W_MAX,
+            interferometer: None,
+        }
+    }
+}
+
+impl Fitacf3Settings {
+    /// The groundscatter thresholds of the settings, for flagging scans the same way as the fit
+    pub fn groundscatter_params(&self) -> GroundscatterParams {
+        GroundscatterParams {
+            v_max: self.gs_velocity,
+            w_max: self.gs_width,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Fitacf3Error {
@@ -37,24 +106,130 @@ impl Display for Fitacf3Error {
 }
 
 pub fn fit_rawacf_record(record: &RawacfRecord, hdw: &HdwInfo) -> Result<FitacfRecord> {
-    let lags = create_lag_list(record);
+    fit_rawacf_record_with_settings(record, hdw, &Fitacf3Settings::default())
+}
+
+/// Same as `fit_rawacf_record`, with non-default fitting thresholds.
+pub fn fit_rawacf_record_with_settings(
+    record: &RawacfRecord,
+    hdw: &HdwInfo,
+    settings: &Fitacf3Settings,
+) -> Result<FitacfRecord> {
+    fit_record(record, hdw, settings, false)
+}
 
+/// Fits every record of a rawacf file, in parallel, and writes the results to a fitacf file.
+/// The hardware parameters are those of the radar at the time of the first record.
+pub fn fit_rawacf_file(infile: &Path, outfile: &Path) -> Result<()> {
+    let rawacf = File::open(infile).map_err(|e| {
+        Fitacf3Error::Message(format!("Unable to open {}: {}", infile.display(), e))
+    })?;
+    let rawacf_records = RawacfRecord::read_records(rawacf)
+        .map_err(|e| Fitacf3Error::Message(format!("Unable to read records: {}", e)))?;
+
+    let rec = rawacf_records
+        .first()
+        .ok_or_else(|| Fitacf3Error::Message("Rawacf file has no records".to_string()))?;
+    let hdw = HdwInfo::for_record(rec).map_err(|e| Fitacf3Error::Message(e.details))?;
+
+    let fitacf_records = rawacf_records
+        .par_iter()
+        .map(|rec| fit_rawacf_record(rec, &hdw))
+        .collect::<Result<Vec<FitacfRecord>>>()?;
+
+    to_file(outfile, &fitacf_records).map_err(|e| {
+        Fitacf3Error::Message(format!("Unable to write {}: {}", outfile.display(), e))
+    })?;
+    Ok(())
+}
+
+/// Same as `fit_rawacf_record`, but filters and fits the ranges of the record in parallel.
+/// The ranges are collected in their original order, so the output is identical.
+pub fn par_fit_rawacf_record(record: &RawacfRecord, hdw: &HdwInfo) -> Result<FitacfRecord> {
+    par_fit_rawacf_record_with_settings(record, hdw, &Fitacf3Settings::default())
+}
+
+/// Same as `par_fit_rawacf_record`, with non-default fitting thresholds.
+pub fn par_fit_rawacf_record_with_settings(
+    record: &RawacfRecord,
+    hdw: &HdwInfo,
+    settings: &Fitacf3Settings,
+) -> Result<FitacfRecord> {
+    fit_record(record, hdw, settings, true)
+}
+
+/// Fits the ranges of a record with lag-zero power, either sequentially or in parallel, and
+/// creates the `FitacfRecord` from the results.
+fn fit_record(
+    record: &RawacfRecord,
+    hdw: &HdwInfo,
+    settings: &Fitacf3Settings,
+    parallel: bool,
+) -> Result<FitacfRecord> {
+    // The noise estimate never converges unless fewer than all of the ranges are averaged
+    if settings.noise_count == 0 || settings.noise_count >= record.num_ranges.max(0) as usize {
+        Err(Fitacf3Error::Message(format!(
+            "Noise count {} must be between 1 and the {} ranges of the record",
+            settings.noise_count, record.num_ranges
+        )))?
+    }
+    let lags = create_lag_list(record)?;
     let noise_power = if record.num_averages <= 0 {
+        warn!(
+            target: LOG_TARGET,
+            "Record has {} averages, skipping noise estimate and lag filtering",
+            record.num_averages
+        );
         1.0
     } else {
-        acf_cutoff_power(record)
+        acf_cutoff_power(record, settings)
     };
-    let mut range_list = vec![];
-    for i in 0..record.range_list.data.len() {
-        let range_num = record.range_list.data[i];
-        if record.lag_zero_power.data[range_num as usize] != 0.0 {
-            range_list.push(RangeNode::new(i, range_num as usize, record, &lags)?)
-        }
+    if record.xcfs.is_none() {
+        debug!(
+            target: LOG_TARGET,
+            "Record on beam {} has no xcfs, skipping elevation", record.beam_num
+        );
     }
+
+    let has_power =
+        |&i: &usize| record.lag_zero_power.data[record.range_list.data[i] as usize] != 0.0;
+    let range_node =
+        |i: usize| RangeNode::new(i, record.range_list.data[i] as usize, record, &lags);
+    let fit = |ranges: Vec<RangeNode>| fit_ranges(record, &lags, ranges, noise_power, settings);
+    let num_ranges = record.range_list.data.len();
+    let range_list = if parallel {
+        // Every stage treats each range on its own, so each range runs through all of them alone
+        (0..num_ranges)
+            .into_par_iter()
+            .filter(has_power)
+            .map(|i| fit(vec![range_node(i)?]))
+            .collect::<Result<Vec<Vec<RangeNode>>>>()?
+            .into_iter()
+            .flatten()
+            .collect()
+    } else {
+        fit((0..num_ranges)
+            .filter(has_power)
+            .map(range_node)
+            .collect::<Result<Vec<RangeNode>>>()?)?
+    };
+    determinations(record, range_list, noise_power, hdw, settings)
+}
+
+/// Runs the filtering and fitting stages on ranges of a record, dropping the ranges that cannot
+/// be fit. Each stage handles every range independently of the others.
+fn fit_ranges(
+    record: &RawacfRecord,
+    lags: &[LagNode],
+    mut range_list: Vec<RangeNode>,
+    noise_power: f32,
+    settings: &Fitacf3Settings,
+) -> Result<Vec<RangeNode>> {
+    filtering::filter_low_snr_ranges(record, &mut range_list, noise_power, settings.min_snr_db);
     filtering::filter_tx_overlapped_lags(record, lags, &mut range_list);
     filtering::filter_infinite_lags(&mut range_list);
-    filtering::filter_low_power_lags(record, &mut range_list);
-    filtering::filter_bad_acfs(record, &mut range_list, noise_power);
+    filtering::filter_low_power_lags(record, &mut range_list, settings);
+    filtering::filter_bad_acfs(record, &mut range_list, noise_power, settings);
     fitting::acf_power_fitting(&mut range_list)?;
     fitting::calculate_phase_and_elev_sigmas(&mut range_list, record)?;
     fitting::acf_phase_unwrap(&mut range_list);
@@ -62,16 +237,33 @@ pub fn fit_rawacf_record(record: &RawacfRecord, hdw: &HdwInfo) -> Result<FitacfR
     filtering::filter_bad_fits(&mut range_list)?;
     fitting::xcf_phase_unwrap(&mut range_list)?;
     fitting::xcf_phase_fitting(&mut range_list)?;
-
-    determinations(record, range_list, noise_power, hdw)
+    Ok(range_list)
 }
 
-/// Creates the lag table based on the data.
-fn create_lag_list(record: &RawacfRecord) -> Vec<LagNode> {
+/// Creates the lag table based on the data. Records without a positive multi-pulse increment,
+/// e.g. from some experimental control programs, have no lag times and cannot be fit.
+fn create_lag_list(record: &RawacfRecord) -> Result<Vec<LagNode>> {
     let lag_table = &record.lag_table;
     let pulse_table = &record.pulse_table;
-    let multi_pulse_increment = record.multi_pulse_increment;
-    let sample_separation = record.sample_separation;
+    if record.multi_pulse_increment <= 0 {
+        warn!(
+            target: LOG_TARGET,
+            "Record from control program {} has multi-pulse increment {}, skipping",
+            record.control_program,
+            record.multi_pulse_increment
+        );
+        Err(Fitacf3Error::Message(format!(
+            "Cannot fit record with multi-pulse increment {}",
+            record.multi_pulse_increment
+        )))?
+    }
+    let tau = samples_per_increment(record)?;
+    if record.sample_separation == 0 {
+        warn!(
+            target: LOG_TARGET,
+            "Record on beam {} has no sample separation, using pulse length", record.beam_num
+        );
+    }
 
     let mut lags = vec![];
     for i in 0..record.num_lags as usize {
@@ -86,10 +278,8 @@ fn create_lag_list(record: &RawacfRecord) -> Vec<LagNode> {
                 pulse_2_idx = j;
             }
         }
-        let sample_base_1 =
-            (lag_table.data[2 * i] * (multi_pulse_increment / sample_separation)) as i32;
-        let sample_base_2 =
-            (lag_table.data[2 * i + 1] * (multi_pulse_increment / sample_separation)) as i32;
+        let sample_base_1 = (lag_table.data[2 * i] * tau) as i32;
+        let sample_base_2 = (lag_table.data[2 * i + 1] * tau) as i32;
         lags.push(LagNode {
             lag_num: number as i32,
             pulses: [pulse_1_idx, pulse_2_idx],
@@ -98,17 +288,23 @@ fn create_lag_list(record: &RawacfRecord) -> Vec<LagNode> {
             sample_base_2,
         });
     }
-    lags
+    Ok(lags)
 }
 
-/// Calculates the minimum power value for ACFs in the record (passing)
-fn acf_cutoff_power(rec: &RawacfRecord) -> f32 {
+/// Estimates the noise level of the record from the lowest-power ranges, following the
+/// fitacf3 `ACF_cutoff_pwr` routine. The mean of up to `noise_count` of the lowest nonzero
+/// lag-zero powers is scaled by a correction for the expected statistical spread, and falls back
+/// to the search noise if the result is below `acf_snr_cutoff`. `noise_count` must be at least 1
+/// and less than the number of ranges of the record, as checked by the fitting functions.
+/// (passing)
+pub fn acf_cutoff_power(rec: &RawacfRecord, settings: &Fitacf3Settings) -> f32 {
+    let noise_count = settings.noise_count as f64;
     let mut sorted_power_levels = rec.lag_zero_power.data.clone();
     sorted_power_levels.sort_by(|a, b| a.total_cmp(b)); // sort floats
     let mut i: usize = 0;
     let mut j: f64 = 0.0;
     let mut min_power: f64 = 0.0;
-    while j < 10.0 && i < rec.num_ranges as usize / 3 {
+    while j < noise_count && i < rec.num_ranges as usize / 3 {
         if sorted_power_levels[i] > 0.0 {
             j += 1.0;
         }
@@ -118,22 +314,44 @@ fn acf_cutoff_power(rec: &RawacfRecord) -> f32 {
     if j <= 0.0 {
         j = 1.0;
     }
-    min_power *= cutoff_power_correction(rec) / j;
-    if min_power < ACF_SNR_CUTOFF && rec.search_noise > 0.0 {
+    min_power *= cutoff_power_correction(rec, noise_count) / j;
+    if min_power < settings.acf_snr_cutoff && rec.search_noise > 0.0 {
+        debug!(
+            target: LOG_TARGET,
+            "Noise estimate {} below cutoff, using search noise {}", min_power, rec.search_noise
+        );
         min_power = rec.search_noise as f64;
     }
     min_power as f32
 }
 
+/// Sky noise estimate of a record, the mean of the 10 lowest lag-zero powers (or of all of them,
+/// for fewer than 10 ranges), clamped to a minimum of 1. Unlike `acf_cutoff_power`, no
+/// statistical correction is applied.
+///
+/// This estimate is not used by the fitting. RST's fitacf3 takes its noise level, including
+/// the `sky_noise` field of the fitacf record, from `acf_cutoff_power`, so using this one
+/// instead would change which ranges are fit and break parity with RST.
+pub fn sky_noise(pwr0: &[f64]) -> f64 {
+    let mut sorted_powers = pwr0.to_vec();
+    sorted_powers.sort_by(|a, b| a.total_cmp(b));
+    let lowest = &sorted_powers[..sorted_powers.len().min(10)];
+    if lowest.is_empty() {
+        return 1.0;
+    }
+    let mean = lowest.iter().sum::<f64>() / lowest.len() as f64;
+    mean.max(1.0)
+}
+
 /// Passing
-fn cutoff_power_correction(rec: &RawacfRecord) -> f64 {
+fn cutoff_power_correction(rec: &RawacfRecord, noise_count: f64) -> f64 {
     let std_dev = 1.0 / (rec.num_averages as f64).sqrt();
 
     let mut i = 0.0;
     let mut cumulative_pdf = 0.0;
     let mut cumulative_pdf_x_norm_power = 0.0;
     let mut normalized_power;
-    while cumulative_pdf < (10.0 / rec.num_ranges as f64) {
+    while cumulative_pdf < (noise_count / rec.num_ranges as f64) {
         // Normalized power for calculating model PDF (Gaussian)
         normalized_power = i / 1000.0;
         let x = -(normalized_power - 1.0) * (normalized_power - 1.0) / (2.0 * std_dev * std_dev);
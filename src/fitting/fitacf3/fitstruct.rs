@@ -12,7 +12,7 @@ pub struct RangeNode {
     pub phase_alpha_2: Vec<f64>,
     pub phases: PhaseNode,
     pub powers: PowerNode,
-    pub elev: PhaseNode,
+    pub elev: Option<PhaseNode>,
     pub lin_pwr_fit: Option<FittedData>,
     pub quad_pwr_fit: Option<FittedData>,
     pub lin_pwr_fit_err: Option<FittedData>,
@@ -28,11 +28,14 @@ impl RangeNode {
         lags: &[LagNode],
     ) -> Result<RangeNode, Fitacf3Error> {
         let cross_range_interference =
-            RangeNode::calculate_cross_range_interference(range_num, record);
+            RangeNode::calculate_cross_range_interference(range_num, record)?;
         let alpha_2 =
             RangeNode::calculate_alphas(range_num, &cross_range_interference, record, lags);
         let phases = PhaseNode::new(record, "acfd", lags, index)?;
-        let elevations = PhaseNode::new(record, "xcfd", lags, index)?;
+        let elevations = match record.xcfs {
+            Some(_) => Some(PhaseNode::new(record, "xcfd", lags, index)?),
+            None => None,
+        };
         let powers = PowerNode::new(record, lags, index, range_num, &alpha_2);
         Ok(RangeNode {
             range_idx: index,
@@ -52,27 +55,28 @@ impl RangeNode {
             elev_fit: None,
         })
     }
-    fn calculate_cross_range_interference(range_num: usize, rec: &RawacfRecord) -> Vec<f64> {
-        let tau: i16 = if rec.sample_separation != 0 {
-            rec.multi_pulse_increment / rec.sample_separation
-        } else {
-            // TODO: Log warning?
-            rec.multi_pulse_increment / rec.tx_pulse_length
-        };
+    fn calculate_cross_range_interference(
+        range_num: usize,
+        rec: &RawacfRecord,
+    ) -> Result<Vec<f64>, Fitacf3Error> {
+        let tau = samples_per_increment(rec)?;
 
         let mut interference_for_pulses: Vec<f64> = vec![];
         for pulse_to_check in 0..rec.num_pulses as usize {
             let mut total_interference: f64 = 0.0;
             for pulse in 0..rec.num_pulses as usize {
-                let pulse_diff = rec.pulse_table.data[pulse_to_check] - rec.pulse_table.data[pulse];
-                let range_to_check = (pulse_diff * tau + range_num as i16) as usize;
-                if (pulse != pulse_to_check) && (range_to_check < rec.num_ranges as usize) {
-                    total_interference += rec.lag_zero_power.data[range_to_check] as f64;
+                let pulse_diff = rec.pulse_table.data[pulse_to_check] as i32
+                    - rec.pulse_table.data[pulse] as i32;
+                // Range interfering with range_num, may be negative or beyond the last range
+                let range_to_check = pulse_diff * tau as i32 + range_num as i32;
+                if (pulse != pulse_to_check) && (0..rec.num_ranges as i32).contains(&range_to_check)
+                {
+                    total_interference += rec.lag_zero_power.data[range_to_check as usize] as f64;
                 }
             }
             interference_for_pulses.push(total_interference);
         }
-        interference_for_pulses
+        Ok(interference_for_pulses)
     }
     fn calculate_alphas(
         range_num: usize,
@@ -191,6 +195,61 @@ impl PowerNode {
     }
 }
 
+/// Number of samples in one multi-pulse increment, i.e. the range separation of echoes from
+/// consecutive pulse table entries. Falls back to the pulse length when the sample separation is
+/// missing; `create_lag_list` logs the fallback once per record.
+pub fn samples_per_increment(rec: &RawacfRecord) -> Result<i16, Fitacf3Error> {
+    if rec.sample_separation != 0 {
+        Ok(rec.multi_pulse_increment / rec.sample_separation)
+    } else if rec.tx_pulse_length != 0 {
+        Ok(rec.multi_pulse_increment / rec.tx_pulse_length)
+    } else {
+        Err(Fitacf3Error::Message(
+            "Record has zero sample separation and zero pulse length".to_string(),
+        ))
+    }
+}
+
+/// Estimates the first-order self-clutter power of each lag of a range, following fitacf3's
+/// `Estimate_First_Order_Self_Clutter`. Each sample of a lag also contains echoes from the other
+/// pulses of the sequence, which come from the ranges `sample - pulse * tau`. The clutter is the
+/// sum of the cross terms between the wanted signal and those echoes, and between the echoes of
+/// the two samples.
+///
+/// This is a standalone diagnostic. The fitting does not add it to the power or phase errors,
+/// which keeps the fitted errors identical to RST's fitacf3 output.
+pub fn self_clutter(
+    lags: &[LagNode],
+    rec: &RawacfRecord,
+    range_num: i32,
+) -> Result<Vec<f64>, Fitacf3Error> {
+    let tau = samples_per_increment(rec)? as i32;
+    let amplitude = |r: i32| -> f64 {
+        if (0..rec.num_ranges as i32).contains(&r) {
+            (rec.lag_zero_power.data[r as usize] as f64).max(0.0).sqrt()
+        } else {
+            0.0
+        }
+    };
+    // Summed amplitude of the echoes in a sample from every pulse but the one being measured
+    let interfering_amplitude = |sample: i32, pulse_idx: usize| -> f64 {
+        (0..rec.num_pulses as usize)
+            .filter(|&i| i != pulse_idx)
+            .map(|i| amplitude(sample - rec.pulse_table.data[i] as i32 * tau))
+            .sum()
+    };
+
+    let signal = amplitude(range_num);
+    Ok(lags
+        .iter()
+        .map(|lag| {
+            let clutter_1 = interfering_amplitude(lag.sample_base_1 + range_num, lag.pulses[0]);
+            let clutter_2 = interfering_amplitude(lag.sample_base_2 + range_num, lag.pulses[1]);
+            signal * clutter_1 + signal * clutter_2 + clutter_1 * clutter_2
+        })
+        .collect())
+}
+
 #[derive(Debug)]
 pub struct LagNode {
     pub lag_num: i32,
@@ -65,9 +65,13 @@ pub fn acf_phase_fitting(ranges: &mut Vec<RangeNode>) -> Result<()> {
 pub fn xcf_phase_fitting(ranges: &mut Vec<RangeNode>) -> Result<()> {
     let lsq = LeastSquares::new(1, 1);
     for mut range in ranges {
-        let phases = &range.elev.phases;
-        let sigmas = &range.elev.std_dev;
-        let t = &range.elev.t;
+        let elev = match range.elev.as_ref() {
+            Some(x) => x,
+            None => continue,
+        };
+        let phases = &elev.phases;
+        let sigmas = &elev.std_dev;
+        let t = &elev.t;
 
         let num_points = t.len();
         if phases.len() != num_points || sigmas.len() != num_points {
@@ -112,7 +116,9 @@ pub fn calculate_phase_and_elev_sigmas(
         range.phases.std_dev = phase_sigmas.clone();
         // Since lag 0 phase is included for elevation fit, set lag 0 sigma the same as lag 1 sigma
         phase_sigmas[0] = phase_sigmas[1];
-        range.elev.std_dev = phase_sigmas; // = elev_sigmas;
+        if let Some(elev) = range.elev.as_mut() {
+            elev.std_dev = phase_sigmas; // = elev_sigmas;
+        }
     }
     Ok(())
 }
@@ -194,9 +200,13 @@ pub fn xcf_phase_unwrap(ranges: &mut Vec<RangeNode>) -> Result<()> {
     for mut range in ranges {
         let (mut sum_xy, mut sum_xx) = (0.0, 0.0);
 
-        let phases = &range.elev.phases;
-        let sigmas = &range.elev.std_dev;
-        let t = &range.elev.t;
+        let elev = match range.elev.as_mut() {
+            Some(x) => x,
+            None => continue,
+        };
+        let phases = &elev.phases;
+        let sigmas = &elev.std_dev;
+        let t = &elev.t;
 
         match range.phase_fit.as_ref() {
             None => Err(Fitacf3Error::Message(
@@ -212,7 +222,7 @@ pub fn xcf_phase_unwrap(ranges: &mut Vec<RangeNode>) -> Result<()> {
                 }
                 let slope_estimate = sum_xy / sum_xx;
                 new_phases = phase_correction(slope_estimate, &new_phases, t).0;
-                range.elev.phases = new_phases;
+                elev.phases = new_phases;
             }
         }
     }
@@ -1,3 +1,5 @@
+#[cfg(feature = "num-complex")]
+pub mod complex;
 pub mod determinations;
 pub mod filtering;
 pub mod fitacf_v3;
@@ -1,3 +1,5 @@
+pub mod coords;
 pub mod error;
 pub mod fitting;
+pub mod scan;
 pub mod utils;
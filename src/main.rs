@@ -1,14 +1,190 @@
-use dmap::formats::{to_file, DmapRecord, RawacfRecord};
+use backscatter_rs::fitting::fitacf3::fitacf_v3::fit_rawacf_file;
+use clap::{Parser, Subcommand};
+use dmap::formats::{DmapRecord, FitacfRecord, RawacfRecord};
+use serde_json::{json, Value};
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
+use std::path::{Path, PathBuf};
+
+pub type BinResult<T, E = Box<dyn std::error::Error + Send + Sync>> = Result<T, E>;
 
 fn main() {
-    let file =
-        File::open("tests/test_files/20210607.1801.00.cly.a.rawacf").expect("Test file not found");
-    // let file = File::open(Path::new("tests/test_files/20160316.1945.01.rkn.iqdat"))
-    //     .expect("Test file not found");
-    // let file = File::open(Path::new("tests/test_files/20110214.map"))
-    //     .expect("Test file not found");
-    let contents = RawacfRecord::read_records(file).unwrap();
-
-    to_file("tests/test_files/temp.rawacf", &contents).unwrap();
+    if let Err(e) = bin_main() {
+        eprintln!("error: {e}");
+        if let Some(e) = e.source() {
+            eprintln!("error: {e}")
+        }
+        std::process::exit(1);
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a summary of each record of a rawacf or fitacf file: time, beam, channel, number
+    /// of ranges and number of ranges with data
+    Dump {
+        /// File to dump. The format is taken from the file extension
+        file: PathBuf,
+
+        /// Only print the record with this index
+        #[arg(short, long)]
+        record: Option<usize>,
+
+        /// Print the summaries as JSON, one object per line
+        #[arg(long)]
+        json: bool,
+
+        /// Print every field of the records instead of a summary
+        #[arg(long, conflicts_with = "json")]
+        full: bool,
+    },
+    /// Fit a rawacf file with fitacf3
+    Fit {
+        /// Rawacf file to fit
+        infile: PathBuf,
+
+        /// Output fitacf file path
+        outfile: PathBuf,
+    },
+}
+
+fn bin_main() -> BinResult<()> {
+    match Cli::parse().command {
+        Command::Dump {
+            file,
+            record,
+            json,
+            full,
+        } => {
+            let format = match (json, full) {
+                (true, _) => DumpFormat::Json,
+                (_, true) => DumpFormat::Full,
+                _ => DumpFormat::Summary,
+            };
+            dump(&file, record, format)
+        }
+        Command::Fit { infile, outfile } => fit(infile, outfile),
+    }
+}
+
+/// How `dump` prints each record
+#[derive(Debug, Clone, Copy)]
+enum DumpFormat {
+    Summary,
+    Json,
+    Full,
+}
+
+/// The fields of a record printed by `dump`
+struct RecordSummary {
+    time: String,
+    beam: i32,
+    channel: i32,
+    num_ranges: i32,
+    valid_ranges: usize,
+}
+
+impl RecordSummary {
+    fn to_json(&self, index: usize) -> Value {
+        json!({
+            "record": index,
+            "time": self.time,
+            "beam": self.beam,
+            "channel": self.channel,
+            "nrang": self.num_ranges,
+            "valid_ranges": self.valid_ranges,
+        })
+    }
+}
+
+impl Display for RecordSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} beam {} channel {} nrang {} valid ranges {}",
+            self.time, self.beam, self.channel, self.num_ranges, self.valid_ranges
+        )
+    }
+}
+
+trait Summary {
+    fn summary(&self) -> RecordSummary;
+}
+
+/// Rawacf and fitacf records share the fields of the summary
+macro_rules! impl_summary {
+    ($($record:ty),+) => {
+        $(impl Summary for $record {
+            fn summary(&self) -> RecordSummary {
+                RecordSummary {
+                    time: format!(
+                        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}",
+                        self.year,
+                        self.month,
+                        self.day,
+                        self.hour,
+                        self.minute,
+                        self.second,
+                        self.microsecond
+                    ),
+                    beam: self.beam_num as i32,
+                    channel: self.channel as i32,
+                    num_ranges: self.num_ranges as i32,
+                    valid_ranges: self.range_list.data.len(),
+                }
+            }
+        })+
+    };
+}
+
+impl_summary!(RawacfRecord, FitacfRecord);
+
+fn dump(path: &Path, record: Option<usize>, format: DumpFormat) -> BinResult<()> {
+    let extension = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default();
+    let file = File::open(path)?;
+    match extension {
+        "rawacf" => print_records(&RawacfRecord::read_records(file)?, record, format),
+        "fitacf" => print_records(&FitacfRecord::read_records(file)?, record, format),
+        _ => Err(format!("Unsupported file type: {}", path.display()))?,
+    }
+}
+
+fn print_records<T: Debug + Summary>(
+    records: &[T],
+    record: Option<usize>,
+    format: DumpFormat,
+) -> BinResult<()> {
+    let selected: Vec<(usize, &T)> = match record {
+        Some(i) => {
+            let rec = records
+                .get(i)
+                .ok_or_else(|| format!("Record {} out of range, file has {}", i, records.len()))?;
+            vec![(i, rec)]
+        }
+        None => records.iter().enumerate().collect(),
+    };
+    for (i, rec) in selected {
+        match format {
+            DumpFormat::Summary => println!("Record {}: {}", i, rec.summary()),
+            DumpFormat::Json => println!("{}", rec.summary().to_json(i)),
+            DumpFormat::Full => println!("Record {}: {:#?}", i, rec),
+        }
+    }
+    Ok(())
+}
+
+fn fit(infile: PathBuf, outfile: PathBuf) -> BinResult<()> {
+    fit_rawacf_file(&infile, &outfile)?;
+    Ok(())
 }
@@ -0,0 +1,132 @@
+use crate::scan::radar_scan::{RadarBeam, RadarCell, RadarScan};
+
+/// Weights of the 3x3x3 (scan, beam, range) filter window, as used by RST's `FilterRadarScan`.
+/// The centre cell has the greatest weight, with the weight halving for each dimension offset.
+pub const FILTER_WEIGHTS: [[[u32; 3]; 3]; 3] = [
+    [[1, 1, 1], [1, 2, 1], [1, 1, 1]],
+    [[1, 2, 1], [2, 4, 2], [1, 2, 1]],
+    [[1, 1, 1], [1, 2, 1], [1, 1, 1]],
+];
+
+#[derive(Debug, Clone)]
+pub struct FilterParams {
+    /// Summed weight of neighbours with scatter that must be exceeded for a cell to survive, out
+    /// of the total window weight of 36. Scaled down for windows cut off at the edge of the data.
+    pub threshold: u32,
+}
+
+impl Default for FilterParams {
+    fn default() -> Self {
+        FilterParams { threshold: 12 }
+    }
+}
+
+/// Median filters each scan using its neighbouring scans, beams and ranges.
+/// A cell keeps scatter if the summed weight of its neighbours with scatter exceeds the
+/// threshold, in which case its parameters are replaced by the weighted median of those
+/// neighbours. Otherwise, the cell is cleared.
+pub fn median_filter(scans: &[RadarScan], params: FilterParams) -> Vec<RadarScan> {
+    let total_weight: u32 = FILTER_WEIGHTS.iter().flatten().flatten().sum();
+    let mut filtered_scans = vec![];
+    for scan_idx in 0..scans.len() {
+        let mut filtered = scans[scan_idx].clone();
+        let first_scan = scan_idx.saturating_sub(1);
+        let last_scan = (scan_idx + 1).min(scans.len() - 1);
+
+        for beam in filtered.beams.iter_mut() {
+            // Neighbouring beams, indexed by [scan offset][beam offset]
+            let mut window: [[Option<&RadarBeam>; 3]; 3] = [[None; 3]; 3];
+            for (s, scan) in scans
+                .iter()
+                .enumerate()
+                .take(last_scan + 1)
+                .skip(first_scan)
+            {
+                for (b, beam_offset) in (-1..=1).enumerate() {
+                    window[s + 1 - scan_idx][b] = scan
+                        .beams
+                        .iter()
+                        .find(|x| x.beam == beam.beam + beam_offset && x.channel == beam.channel);
+                }
+            }
+
+            for (range, cell) in beam.cells.iter_mut().enumerate() {
+                let mut available_weight = 0;
+                let mut scatter_weight = 0;
+                let mut neighbours: Vec<(&RadarCell, u32)> = vec![];
+                for (s, window_scan) in window.iter().enumerate() {
+                    for (b, window_beam) in window_scan.iter().enumerate() {
+                        let window_beam = match window_beam {
+                            Some(x) => x,
+                            None => continue,
+                        };
+                        for r in range.saturating_sub(1)..(range + 2) {
+                            let neighbour = match window_beam.cells.get(r) {
+                                Some(x) => x,
+                                None => continue,
+                            };
+                            let weight = FILTER_WEIGHTS[s][b][r + 1 - range];
+                            available_weight += weight;
+                            if neighbour.sct {
+                                scatter_weight += weight;
+                                neighbours.push((neighbour, weight));
+                            }
+                        }
+                    }
+                }
+                let threshold = params.threshold * available_weight / total_weight;
+                if scatter_weight <= threshold {
+                    clear_cell(cell);
+                    continue;
+                }
+                let ground_weight: u32 = neighbours
+                    .iter()
+                    .filter(|(x, _)| x.groundscatter)
+                    .map(|(_, w)| w)
+                    .sum();
+                cell.sct = true;
+                cell.groundscatter = 2 * ground_weight > scatter_weight;
+                cell.power_lag_zero = weighted_median(&neighbours, |x| x.power_lag_zero);
+                cell.power_error_lag_zero =
+                    weighted_median(&neighbours, |x| x.power_error_lag_zero);
+                cell.velocity = weighted_median(&neighbours, |x| x.velocity);
+                cell.velocity_error = weighted_median(&neighbours, |x| x.velocity_error);
+                cell.power_lin = weighted_median(&neighbours, |x| x.power_lin);
+                cell.power_lin_error = weighted_median(&neighbours, |x| x.power_lin_error);
+                cell.spectral_width_lin = weighted_median(&neighbours, |x| x.spectral_width_lin);
+                cell.spectral_width_lin_error =
+                    weighted_median(&neighbours, |x| x.spectral_width_lin_error);
+                cell.phi_0 = weighted_median(&neighbours, |x| x.phi_0);
+                // Only neighbours from records with xcfs have an elevation
+                let with_elevation: Vec<(&RadarCell, u32)> = neighbours
+                    .iter()
+                    .filter(|(x, _)| x.elevation.is_some())
+                    .copied()
+                    .collect();
+                cell.elevation = (!with_elevation.is_empty())
+                    .then(|| weighted_median(&with_elevation, |x| x.elevation.unwrap_or_default()));
+            }
+        }
+        filtered_scans.push(filtered);
+    }
+    filtered_scans
+}
+
+/// Median of one cell parameter, with each cell counted as many times as its weight.
+fn weighted_median<F: Fn(&RadarCell) -> f64>(cells: &[(&RadarCell, u32)], param: F) -> f64 {
+    let mut values: Vec<(f64, u32)> = cells.iter().map(|(c, w)| (param(c), *w)).collect();
+    values.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let total: u32 = values.iter().map(|(_, w)| w).sum();
+    let mut cumulative = 0;
+    for (value, weight) in values.iter() {
+        cumulative += weight;
+        if 2 * cumulative > total {
+            return *value;
+        }
+    }
+    0.0
+}
+
+fn clear_cell(cell: &mut RadarCell) {
+    *cell = RadarCell::default();
+}
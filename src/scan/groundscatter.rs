@@ -0,0 +1,36 @@
+use crate::scan::radar_scan::{RadarCell, RadarScan};
+
+/// Thresholds of the groundscatter criterion of RST's `Determinations`, which flags low
+/// velocity, narrow spectral width echoes as groundscatter. `Fitacf3Settings::groundscatter_params`
+/// gives the thresholds used by the fit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroundscatterParams {
+    /// Velocity limit in m/s at zero spectral width
+    pub v_max: f32,
+    /// Spectral width in m/s at which the velocity limit reaches zero
+    pub w_max: f32,
+}
+
+impl GroundscatterParams {
+    /// Whether scatter with the given velocity and linear spectral width in m/s is
+    /// groundscatter, by the criterion `|v| - (v_max - w * v_max / w_max) < 1`.
+    pub fn is_groundscatter(&self, velocity: f32, spectral_width: f32) -> bool {
+        velocity.abs() - (self.v_max - spectral_width * (self.v_max / self.w_max)) < 1.0
+    }
+}
+
+/// Sets the groundscatter flag of a cell based on its velocity and linear spectral width.
+pub fn flag_groundscatter(cell: &mut RadarCell, params: &GroundscatterParams) {
+    cell.groundscatter =
+        params.is_groundscatter(cell.velocity as f32, cell.spectral_width_lin as f32);
+}
+
+/// Re-flags groundscatter for every cell with scatter in the scan. Scans built by
+/// `scan_from_fitacf` carry the flags of the fitacf records, this overrides them.
+pub fn flag_scan_groundscatter(scan: &mut RadarScan, params: &GroundscatterParams) {
+    for beam in scan.beams.iter_mut() {
+        for cell in beam.cells.iter_mut().filter(|c| c.sct) {
+            flag_groundscatter(cell, params);
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod filter;
+pub mod groundscatter;
+pub mod radar_scan;
@@ -0,0 +1,335 @@
+use crate::error::BackscatterError;
+use chrono::NaiveDate;
+use dmap::formats::FitacfRecord;
+
+type Result<T> = std::result::Result<T, BackscatterError>;
+
+/// Fitted parameters of a single range gate, mirroring RST's `RadarCell`. The default is a
+/// cell without scatter, with all parameters zeroed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RadarCell {
+    pub sct: bool,
+    pub groundscatter: bool,
+    pub power_lag_zero: f64,
+    pub power_error_lag_zero: f64,
+    pub velocity: f64,
+    pub velocity_error: f64,
+    pub power_lin: f64,
+    pub power_lin_error: f64,
+    pub spectral_width_lin: f64,
+    pub spectral_width_lin_error: f64,
+    pub phi_0: f64,
+    /// Elevation angle in degrees, `None` for records without xcfs
+    pub elevation: Option<f64>,
+}
+
+/// A single beam sounding within a scan, mirroring RST's `RadarBeam`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RadarBeam {
+    pub scan: i32,
+    pub beam: i32,
+    pub beam_azimuth: f64,
+    pub time: f64,
+    pub control_program: i32,
+    pub intt_second: i32,
+    pub intt_microsecond: i32,
+    pub num_averages: i32,
+    pub first_range: i32,
+    pub range_sep: i32,
+    pub rx_rise_time: i32,
+    pub tx_freq: i32,
+    pub noise: f64,
+    pub attenuation: i32,
+    pub channel: i32,
+    pub num_ranges: i32,
+    pub cells: Vec<RadarCell>,
+}
+
+impl RadarBeam {
+    /// Cells of the beam with scatter.
+    pub fn valid_cells(&self) -> impl Iterator<Item = &RadarCell> {
+        self.cells.iter().filter(|c| c.sct)
+    }
+}
+
+/// A full scan of the radar, mirroring RST's `RadarScan`. Times are in seconds since the epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RadarScan {
+    pub station_id: i32,
+    pub version_major: i32,
+    pub version_minor: i32,
+    pub start_time: f64,
+    pub end_time: f64,
+    pub num: usize,
+    pub beams: Vec<RadarBeam>,
+}
+
+/// How the beams of radars running several channels are assembled into scans.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChannelMode {
+    /// Each channel forms its own sequence of scans, as in RST. The scans of all channels are
+    /// returned in order of start time, with scans starting together in order of the channel's
+    /// first record.
+    #[default]
+    Separate,
+    /// Beams of all channels are merged into one sequence of scans, in time order. Scans are
+    /// split only at the scan flags of the channel of the first record, so the other channels
+    /// do not start a new scan when their own scan begins.
+    Merge,
+    /// Only beams of the given channel are used.
+    Only(i32),
+}
+
+/// Builds a single `RadarScan` out of all the records given, in the same way as RST's
+/// `FitToRadarScan`. Records must all come from the same radar. The groundscatter flags of the
+/// cells are those of the records; use `flag_scan_groundscatter` to re-flag them with other
+/// thresholds.
+pub fn scan_from_fitacf(records: &[FitacfRecord]) -> Result<RadarScan> {
+    scan_from_records(&records.iter().collect::<Vec<_>>())
+}
+
+/// Splits the records into consecutive scans. A new scan starts at every record with a scan
+/// flag of +/-1, as in RST's `FitReadRadarScan`. Channels are handled according to `mode`.
+pub fn scans_from_fitacf(records: &[FitacfRecord], mode: ChannelMode) -> Result<Vec<RadarScan>> {
+    match mode {
+        ChannelMode::Separate => {
+            let mut channels: Vec<i32> = vec![];
+            for rec in records {
+                if !channels.contains(&(rec.channel as i32)) {
+                    channels.push(rec.channel as i32);
+                }
+            }
+            let mut scans = vec![];
+            for channel in channels {
+                let channel_records: Vec<&FitacfRecord> = records
+                    .iter()
+                    .filter(|r| r.channel as i32 == channel)
+                    .collect();
+                scans.extend(split_scans(&channel_records, None)?);
+            }
+            sort_by_time(&mut scans, |s| s.start_time);
+            Ok(scans)
+        }
+        ChannelMode::Merge => {
+            let mut timed_records = vec![];
+            for rec in records {
+                timed_records.push((record_epoch(rec)?, rec));
+            }
+            sort_by_time(&mut timed_records, |r| r.0);
+            let sorted: Vec<&FitacfRecord> = timed_records.into_iter().map(|(_, r)| r).collect();
+            let boundary_channel = records.first().map(|r| r.channel as i32);
+            split_scans(&sorted, boundary_channel)
+        }
+        ChannelMode::Only(channel) => {
+            let channel_records: Vec<&FitacfRecord> = records
+                .iter()
+                .filter(|r| r.channel as i32 == channel)
+                .collect();
+            split_scans(&channel_records, None)
+        }
+    }
+}
+
+/// Splits records at their scan flags. If `boundary_channel` is given, only records of that
+/// channel can start a new scan.
+fn split_scans(records: &[&FitacfRecord], boundary_channel: Option<i32>) -> Result<Vec<RadarScan>> {
+    let mut scans = vec![];
+    let mut scan_start = 0;
+    for (i, rec) in records.iter().enumerate() {
+        let is_boundary = rec.scan_flag.abs() == 1
+            && (boundary_channel.is_none() || boundary_channel == Some(rec.channel as i32));
+        if is_boundary && i > scan_start {
+            scans.push(scan_from_records(&records[scan_start..i])?);
+            scan_start = i;
+        }
+    }
+    if scan_start < records.len() {
+        scans.push(scan_from_records(&records[scan_start..])?);
+    }
+    Ok(scans)
+}
+
+fn scan_from_records(records: &[&FitacfRecord]) -> Result<RadarScan> {
+    let first = records
+        .first()
+        .ok_or_else(|| BackscatterError::new("Cannot create scan from zero records"))?;
+    let mut beams = vec![];
+    for &rec in records {
+        if rec.station_id != first.station_id {
+            Err(BackscatterError::new(
+                "Cannot create scan from records of multiple radars",
+            ))?
+        }
+        beams.push(beam_from_fitacf(rec)?);
+    }
+    Ok(scan_with_beams(
+        first.station_id as i32,
+        first.fitacf_revision_major,
+        first.fitacf_revision_minor,
+        beams,
+    ))
+}
+
+/// Concatenates scans of the same radar into one, with the beams sorted by time.
+pub fn merge_scans(scans: Vec<RadarScan>) -> Result<RadarScan> {
+    let first = scans
+        .first()
+        .ok_or_else(|| BackscatterError::new("Cannot merge zero scans"))?;
+    let (station_id, version_major, version_minor) =
+        (first.station_id, first.version_major, first.version_minor);
+    let mut beams = vec![];
+    for scan in scans {
+        if scan.station_id != station_id {
+            Err(BackscatterError::new(
+                "Cannot merge scans of multiple radars",
+            ))?
+        }
+        beams.extend(scan.beams);
+    }
+    sort_by_time(&mut beams, |b| b.time);
+    Ok(scan_with_beams(
+        station_id,
+        version_major,
+        version_minor,
+        beams,
+    ))
+}
+
+/// Sorts by a time in seconds. The sort is stable, so items at the same time keep their order.
+fn sort_by_time<T>(items: &mut [T], time: impl Fn(&T) -> f64) {
+    items.sort_by(|a, b| time(a).total_cmp(&time(b)));
+}
+
+/// Splits a scan into consecutive windows of `window_secs` seconds, starting from the start of
+/// the scan. Windows without any beams are left out.
+pub fn window_scans(scan: &RadarScan, window_secs: f64) -> Result<Vec<RadarScan>> {
+    if window_secs <= 0.0 || !window_secs.is_finite() {
+        Err(BackscatterError::new(
+            "Scan window must be a positive duration",
+        ))?
+    }
+    let mut beams = scan.beams.clone();
+    sort_by_time(&mut beams, |b| b.time);
+
+    let mut scans = vec![];
+    let mut window_beams: Vec<RadarBeam> = vec![];
+    let mut current_window = 0;
+    for beam in beams {
+        let window = ((beam.time - scan.start_time) / window_secs).floor() as i64;
+        if window != current_window && !window_beams.is_empty() {
+            scans.push(scan_with_beams(
+                scan.station_id,
+                scan.version_major,
+                scan.version_minor,
+                std::mem::take(&mut window_beams),
+            ));
+        }
+        current_window = window;
+        window_beams.push(beam);
+    }
+    if !window_beams.is_empty() {
+        scans.push(scan_with_beams(
+            scan.station_id,
+            scan.version_major,
+            scan.version_minor,
+            window_beams,
+        ));
+    }
+    Ok(scans)
+}
+
+/// Creates a scan from its beams, with the times spanning all of the beams
+fn scan_with_beams(
+    station_id: i32,
+    version_major: i32,
+    version_minor: i32,
+    beams: Vec<RadarBeam>,
+) -> RadarScan {
+    RadarScan {
+        station_id,
+        version_major,
+        version_minor,
+        start_time: beams.iter().map(|b| b.time).fold(f64::INFINITY, f64::min),
+        end_time: beams
+            .iter()
+            .map(|b| b.time)
+            .fold(f64::NEG_INFINITY, f64::max),
+        num: beams.len(),
+        beams,
+    }
+}
+
+fn beam_from_fitacf(rec: &FitacfRecord) -> Result<RadarBeam> {
+    let mut cells = vec![RadarCell::default(); rec.num_ranges.max(0) as usize];
+    for (i, &range_num) in rec.range_list.data.iter().enumerate() {
+        let cell = cells
+            .get_mut(range_num as usize)
+            .ok_or_else(|| BackscatterError::new("Range list exceeds number of ranges"))?;
+        cell.sct = true;
+        cell.groundscatter = rec.ground_flag.data[i] != 0;
+        cell.power_lag_zero = rec.lag_zero_power.data[range_num as usize] as f64;
+        cell.power_error_lag_zero =
+            lag_zero_power_error(cell.power_lag_zero, rec.num_averages as i32);
+        cell.velocity = rec.velocity.data[i] as f64;
+        cell.velocity_error = rec.velocity_error.data[i] as f64;
+        cell.power_lin = rec.lambda_power.data[i] as f64;
+        cell.power_lin_error = rec.lambda_power_error.data[i] as f64;
+        cell.spectral_width_lin = rec.lambda_spectral_width.data[i] as f64;
+        cell.spectral_width_lin_error = rec.lambda_spectral_width_error.data[i] as f64;
+        cell.phi_0 = rec
+            .lag_zero_phi
+            .as_ref()
+            .map_or(0.0, |phi| phi.data[i] as f64);
+        cell.elevation = rec.elevation.as_ref().map(|e| e.data[i] as f64);
+    }
+    Ok(RadarBeam {
+        scan: rec.scan_flag as i32,
+        beam: rec.beam_num as i32,
+        beam_azimuth: rec.beam_azimuth as f64,
+        time: record_epoch(rec)?,
+        control_program: rec.control_program as i32,
+        intt_second: rec.intt_second as i32,
+        intt_microsecond: rec.intt_microsecond,
+        num_averages: rec.num_averages as i32,
+        first_range: rec.first_range as i32,
+        range_sep: rec.range_sep as i32,
+        rx_rise_time: rec.rx_rise_time as i32,
+        tx_freq: rec.tx_freq as i32,
+        noise: rec.search_noise as f64,
+        attenuation: rec.attenuation as i32,
+        channel: rec.channel as i32,
+        num_ranges: rec.num_ranges as i32,
+        cells,
+    })
+}
+
+/// Linear signal-to-noise ratio of a power in dB above the noise level, as stored in `pwr0`.
+pub fn snr(power_db: f64) -> f64 {
+    10.0_f64.powf(power_db / 10.0)
+}
+
+/// Error in dB of the lag-zero power, as given in dB above the noise level.
+/// The measured power (signal plus noise) averaged over `num_averages` sequences has a
+/// relative statistical error of 1/sqrt(nave), which is propagated to the signal power in dB.
+pub fn lag_zero_power_error(power_db: f64, num_averages: i32) -> f64 {
+    if num_averages <= 0 {
+        return 0.0;
+    }
+    let snr = snr(power_db);
+    10.0 / std::f64::consts::LN_10 * (1.0 + 1.0 / snr) / (num_averages as f64).sqrt()
+}
+
+/// Seconds since 1970-01-01 of the record timestamp, including the microseconds
+pub fn record_epoch(rec: &FitacfRecord) -> Result<f64> {
+    let datetime = NaiveDate::from_ymd_opt(rec.year as i32, rec.month as u32, rec.day as u32)
+        .and_then(|d| {
+            d.and_hms_micro_opt(
+                rec.hour as u32,
+                rec.minute as u32,
+                rec.second as u32,
+                rec.microsecond as u32,
+            )
+        })
+        .ok_or_else(|| BackscatterError::new("Unable to interpret record timestamp"))?;
+    Ok(datetime.timestamp_micros() as f64 * 1.0e-6)
+}
@@ -1,5 +1,6 @@
 use crate::error::BackscatterError;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime};
+use dmap::formats::RawacfRecord;
 use rust_embed::RustEmbed;
 use std::io::{BufRead, BufReader};
 
@@ -32,6 +33,14 @@ pub struct HdwInfo {
 }
 
 impl HdwInfo {
+    /// Hardware parameters of the radar that produced a record, valid at the time of the record.
+    pub fn for_record(rec: &RawacfRecord) -> Result<HdwInfo, BackscatterError> {
+        let datetime = NaiveDate::from_ymd_opt(rec.year as i32, rec.month as u32, rec.day as u32)
+            .and_then(|d| d.and_hms_opt(rec.hour as u32, rec.minute as u32, rec.second as u32))
+            .ok_or_else(|| BackscatterError::new("Unable to interpret record timestamp"))?;
+        HdwInfo::new(rec.station_id, datetime)
+    }
+
     pub fn new(station_id: i16, datetime: NaiveDateTime) -> Result<HdwInfo, BackscatterError> {
         let site_name = match station_id {
             209 => "ade",
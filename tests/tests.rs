@@ -1,29 +1,42 @@
-use backscatter_rs::fitting::fitacf3::fitacf_v3::fit_rawacf_record;
+use backscatter_rs::coords::geolocation::{cell_geolocation, slant_range, VirtualHeight};
+use backscatter_rs::coords::magnetic::{geo_to_aacgm, MagneticCoord};
+use backscatter_rs::error::BackscatterError;
+use backscatter_rs::fitting::fitacf3::determinations::{
+    decay_to_width, sigma_decay_to_width, slope_to_velocity, InterferometerGeometry,
+};
+use backscatter_rs::fitting::fitacf3::filtering::{blanked_lags, range_passes_snr};
+use backscatter_rs::fitting::fitacf3::fitacf_v3::{
+    acf_cutoff_power, fit_rawacf_record, fit_rawacf_record_with_settings, par_fit_rawacf_record,
+    par_fit_rawacf_record_with_settings, sky_noise, Fitacf3Settings,
+};
+use backscatter_rs::fitting::fitacf3::fitstruct::{self_clutter, FitType, LagNode, RangeNode};
+use backscatter_rs::fitting::fitacf3::least_squares::LeastSquares;
+use backscatter_rs::scan::filter::{median_filter, FilterParams};
+use backscatter_rs::scan::groundscatter::{flag_groundscatter, flag_scan_groundscatter};
+use backscatter_rs::scan::radar_scan::{
+    lag_zero_power_error, merge_scans, record_epoch, scan_from_fitacf, scans_from_fitacf, snr,
+    window_scans, ChannelMode, RadarBeam, RadarCell, RadarScan,
+};
 use backscatter_rs::utils::hdw::HdwInfo;
-use chrono::NaiveDateTime;
 use dmap::formats::{DmapRecord, FitacfRecord, RawacfRecord};
+use is_close::is_close;
 use std::fs::{remove_file, File};
 use std::iter::zip;
 
+/// The records of test.rawacf, and the hardware parameters of the radar at the first of them
+fn load_test_rawacf() -> (Vec<RawacfRecord>, HdwInfo) {
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let hdw = HdwInfo::for_record(&rawacf[0]).expect("Unable to read hdw file");
+    (rawacf, hdw)
+}
+
 #[test]
 fn test_fitacf3() {
     // Create fitacf file from rawacf file
-    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
-    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let (rawacf, hdw) = load_test_rawacf();
     let mut fitacf_records = vec![];
 
-    let rec = &rawacf[0];
-    let file_datetime = NaiveDateTime::parse_from_str(
-        format!(
-            "{:4}{:0>2}{:0>2} {:0>2}:{:0>2}:{:0>2}",
-            rec.year, rec.month, rec.day, rec.hour, rec.minute, rec.second
-        )
-        .as_str(),
-        "%Y%m%d %H:%M:%S",
-    )
-    .expect("Unable to interpret record timestamp");
-    let hdw = HdwInfo::new(rec.station_id, file_datetime).expect("Unable to read utils file");
-
     for rec in rawacf {
         fitacf_records.push(fit_rawacf_record(&rec, &hdw).expect("Could not fit record"));
     }
@@ -38,3 +51,838 @@ fn test_fitacf3() {
     }
     remove_file("tests/test_files/temp.fitacf").expect("Unable to delete file");
 }
+
+#[test]
+fn test_fitacf3_zero_pulse_length() {
+    let (rawacf, hdw) = load_test_rawacf();
+    let mut rec = rawacf
+        .into_iter()
+        .find(|r| {
+            r.range_list
+                .data
+                .iter()
+                .any(|&x| r.lag_zero_power.data[x as usize] != 0.0)
+        })
+        .expect("No record with ranges to fit");
+
+    rec.sample_separation = 0;
+    rec.tx_pulse_length = 0;
+    assert!(fit_rawacf_record(&rec, &hdw).is_err());
+}
+
+#[test]
+fn test_fitacf3_without_xcfs() {
+    let (rawacf, hdw) = load_test_rawacf();
+
+    for rec in rawacf {
+        let with_xcfs = fit_rawacf_record(&rec, &hdw).expect("Could not fit record");
+        let mut single_antenna = rec;
+        single_antenna.xcfs = None;
+        let without_xcfs =
+            fit_rawacf_record(&single_antenna, &hdw).expect("Could not fit record without xcfs");
+
+        assert_eq!(without_xcfs.elevation, None);
+        assert_eq!(without_xcfs.lag_zero_phi, None);
+        assert_eq!(without_xcfs.range_list, with_xcfs.range_list);
+        assert_eq!(without_xcfs.velocity, with_xcfs.velocity);
+        assert_eq!(without_xcfs.lambda_power, with_xcfs.lambda_power);
+    }
+}
+
+#[test]
+fn test_self_clutter() {
+    let mut rec = load_test_rawacf().0.remove(0);
+    let tau = (rec.multi_pulse_increment / rec.sample_separation) as i32;
+
+    // Two pulse sequence, so each sample has a single interfering echo
+    rec.pulse_table.data = vec![0, 1];
+    rec.num_pulses = 2;
+    let lags = [LagNode {
+        lag_num: 1,
+        pulses: [0, 1],
+        lag_idx: 0,
+        sample_base_1: 0,
+        sample_base_2: tau,
+    }];
+    let range = 2 * tau;
+    for p in rec.lag_zero_power.data.iter_mut() {
+        *p = 0.0;
+    }
+    rec.lag_zero_power.data[range as usize] = 4.0;
+    let clutter = |rec: &RawacfRecord| -> f64 {
+        self_clutter(&lags, rec, range).expect("Could not estimate self-clutter")[0]
+    };
+    assert_eq!(clutter(&rec), 0.0);
+
+    // Sample 1 sees range - tau from the second pulse, sample 2 sees range + tau from the first
+    rec.lag_zero_power.data[(range - tau) as usize] = 9.0;
+    rec.lag_zero_power.data[(range + tau) as usize] = 16.0;
+    assert!(is_close!(clutter(&rec), 2.0 * 3.0 + 2.0 * 4.0 + 3.0 * 4.0));
+
+    // Without a sample separation, the pulse length gives the same number of samples per lag
+    rec.tx_pulse_length = rec.sample_separation;
+    rec.sample_separation = 0;
+    assert!(is_close!(clutter(&rec), 2.0 * 3.0 + 2.0 * 4.0 + 3.0 * 4.0));
+    rec.tx_pulse_length = 0;
+    assert!(self_clutter(&lags, &rec, range).is_err());
+}
+
+#[test]
+fn test_interferometer_geometry() {
+    let (rawacf, hdw) = load_test_rawacf();
+    let (rec, default_fit) = rawacf
+        .iter()
+        .map(|r| (r, fit_rawacf_record(r, &hdw).expect("Could not fit record")))
+        .find(|(_, fit)| !fit.range_list.data.is_empty())
+        .expect("No record with fitted ranges");
+
+    // Interferometer in front of the main array, as given in the hdw file
+    let in_front = Fitacf3Settings {
+        interferometer: Some(InterferometerGeometry::from_hdw(&hdw)),
+        ..Default::default()
+    };
+    let in_front_fit =
+        fit_rawacf_record_with_settings(rec, &hdw, &in_front).expect("Could not fit record");
+    assert_eq!(in_front_fit.elevation, default_fit.elevation);
+
+    // Interferometer behind the main array
+    let mut geometry = InterferometerGeometry::from_hdw(&hdw);
+    geometry.offset_y *= -1.0;
+    let behind = Fitacf3Settings {
+        interferometer: Some(geometry),
+        ..Default::default()
+    };
+    let behind_fit =
+        fit_rawacf_record_with_settings(rec, &hdw, &behind).expect("Could not fit record");
+    assert_ne!(behind_fit.elevation, default_fit.elevation);
+    assert_eq!(behind_fit.velocity, default_fit.velocity);
+}
+
+#[test]
+fn test_blanked_lags() {
+    let lag = |lag_num, sample_base_1, sample_base_2| LagNode {
+        lag_num,
+        pulses: [0, 0],
+        lag_idx: 0,
+        sample_base_1,
+        sample_base_2,
+    };
+    // Pulses at 0, 14 and 22 increments, with one sample per increment
+    let lags = vec![lag(0, 0, 0), lag(8, 14, 22), lag(14, 0, 14), lag(22, 0, 22)];
+    // Samples blanked by the pulse at 14 increments
+    let bad_samples = vec![14, 15];
+
+    // A lag is missing wherever either of its samples lands on a blanked sample
+    assert_eq!(blanked_lags(&lags, &bad_samples, 0), vec![1, 2]);
+    assert_eq!(blanked_lags(&lags, &bad_samples, 1), vec![1, 2]);
+    assert_eq!(blanked_lags(&lags, &bad_samples, 2), Vec::<usize>::new());
+    assert_eq!(blanked_lags(&lags, &bad_samples, 14), vec![0, 2, 3]);
+}
+
+#[test]
+fn test_fitacf3_settings() {
+    let (rawacf, hdw) = load_test_rawacf();
+
+    let strict = Fitacf3Settings {
+        min_snr_db: 10.0,
+        ..Default::default()
+    };
+    let mut dropped = 0;
+    for rec in rawacf.iter() {
+        let default_fit = fit_rawacf_record(rec, &hdw).expect("Could not fit record");
+        let settings_fit = fit_rawacf_record_with_settings(rec, &hdw, &Fitacf3Settings::default())
+            .expect("Could not fit record");
+        assert_eq!(settings_fit, default_fit);
+
+        // A higher SNR threshold only ever removes ranges
+        let strict_fit =
+            fit_rawacf_record_with_settings(rec, &hdw, &strict).expect("Could not fit record");
+        assert!(strict_fit
+            .range_list
+            .data
+            .iter()
+            .all(|r| default_fit.range_list.data.contains(r)));
+        dropped += default_fit.range_list.data.len() - strict_fit.range_list.data.len();
+    }
+    assert!(dropped > 0);
+
+    // Noise counts that give no noise estimate are rejected rather than fit
+    let rec = &rawacf[0];
+    for noise_count in [0, rec.num_ranges as usize, 100 + rec.num_ranges as usize] {
+        let settings = Fitacf3Settings {
+            noise_count,
+            ..Default::default()
+        };
+        assert!(fit_rawacf_record_with_settings(rec, &hdw, &settings).is_err());
+        assert!(par_fit_rawacf_record_with_settings(rec, &hdw, &settings).is_err());
+    }
+}
+
+#[test]
+fn test_fitacf3_shared_settings() {
+    fn assert_shareable<T: Send + Sync>() {}
+    assert_shareable::<Fitacf3Settings>();
+    assert_shareable::<HdwInfo>();
+
+    let (rawacf, hdw) = load_test_rawacf();
+
+    // One configured instance, used by every thread without cloning
+    let settings = Fitacf3Settings::default();
+    let fits: Vec<Vec<FitacfRecord>> = std::thread::scope(|s| {
+        let handles: Vec<_> = rawacf
+            .chunks(rawacf.len().div_ceil(4).max(1))
+            .map(|chunk| {
+                s.spawn(|| {
+                    chunk
+                        .iter()
+                        .map(|r| {
+                            fit_rawacf_record_with_settings(r, &hdw, &settings)
+                                .expect("Could not fit record")
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("Fitting thread panicked"))
+            .collect()
+    });
+    for (r, threaded) in zip(rawacf.iter(), fits.iter().flatten()) {
+        let fit = fit_rawacf_record(r, &hdw).expect("Could not fit record");
+        assert_eq!(threaded.range_list, fit.range_list);
+        assert_eq!(threaded.velocity, fit.velocity);
+    }
+}
+
+#[test]
+fn test_par_fitacf3_matches_sequential() {
+    let (rawacf, hdw) = load_test_rawacf();
+
+    macro_rules! assert_fields_eq {
+        ($par:expr, $seq:expr, $($field:ident),+) => {
+            $(assert_eq!($par.$field, $seq.$field, "{} differs", stringify!($field));)+
+        };
+    }
+    for rec in rawacf.iter() {
+        let seq = fit_rawacf_record(rec, &hdw).expect("Could not fit record");
+        let par = par_fit_rawacf_record(rec, &hdw).expect("Could not fit record");
+        assert_fields_eq!(
+            par,
+            seq,
+            range_list,
+            fitted_points,
+            quality_flag,
+            ground_flag,
+            lambda_power,
+            lambda_power_error,
+            sigma_power,
+            sigma_power_error,
+            velocity,
+            velocity_error,
+            lambda_spectral_width,
+            lambda_spectral_width_error,
+            sigma_spectral_width,
+            sigma_spectral_width_error,
+            lambda_std_dev,
+            sigma_std_dev,
+            phi_std_dev,
+            lag_zero_phi,
+            lag_zero_phi_error,
+            elevation,
+            elevation_error,
+            sky_noise,
+            lag_zero_noise,
+            velocity_noise
+        );
+        assert_eq!(par, seq);
+    }
+}
+
+#[test]
+fn test_fitacf3_zero_multi_pulse_increment() {
+    let (mut rawacf, hdw) = load_test_rawacf();
+    let mut rec = rawacf.remove(0);
+
+    rec.multi_pulse_increment = 0;
+    assert!(fit_rawacf_record(&rec, &hdw).is_err());
+    assert!(par_fit_rawacf_record(&rec, &hdw).is_err());
+}
+
+#[test]
+fn test_cross_range_interference() {
+    let mut rec = load_test_rawacf().0.remove(0);
+    // Pulses at 0, 2 and 3 increments, with one sample per increment
+    rec.num_pulses = 3;
+    rec.pulse_table.data[0] = 0;
+    rec.pulse_table.data[1] = 2;
+    rec.pulse_table.data[2] = 3;
+    rec.sample_separation = 300;
+    rec.multi_pulse_increment = 300;
+    // Power of range r is r + 1, so each term identifies its range
+    for (r, p) in rec.lag_zero_power.data.iter_mut().enumerate() {
+        *p = (r + 1) as f32;
+    }
+    let num_ranges = rec.num_ranges as f64;
+    let lag = |lag_num, pulses, lag_idx| LagNode {
+        lag_num,
+        pulses,
+        lag_idx,
+        sample_base_1: 0,
+        sample_base_2: 0,
+    };
+    let lags = vec![lag(0, [0, 0], 0), lag(1, [1, 2], 1), lag(2, [0, 1], 2)];
+    let interference = |range_num: usize| {
+        RangeNode::new(0, range_num, &rec, &lags)
+            .expect("Unable to create range node")
+            .cross_range_interference
+    };
+
+    // Echoes of pulse p in the sample for pulse q come from range_num + (q - p) * tau, so
+    // later pulses pick up nearer ranges and earlier pulses farther ones
+    assert_eq!(interference(5), vec![4.0 + 3.0, 8.0 + 5.0, 9.0 + 7.0]);
+    // Interfering ranges before the first range are left out
+    assert_eq!(interference(1), vec![0.0, 4.0 + 1.0, 5.0 + 3.0]);
+    // As are those beyond the last range
+    let last = rec.num_ranges as usize - 1;
+    assert_eq!(
+        interference(last - 1),
+        vec![
+            (num_ranges - 3.0) + (num_ranges - 4.0),
+            num_ranges - 2.0,
+            num_ranges
+        ]
+    );
+}
+
+#[test]
+fn test_acf_cutoff_power() {
+    let mut rec = load_test_rawacf().0.remove(0);
+    let settings = Fitacf3Settings::default();
+    rec.num_averages = 20;
+    rec.num_ranges = 30;
+    rec.search_noise = 5.0;
+
+    // Flat noise floor of 100 with a few strong ranges, which must not affect the estimate
+    for p in rec.lag_zero_power.data.iter_mut() {
+        *p = 100.0;
+    }
+    for r in [2, 7, 11, 19, 23] {
+        rec.lag_zero_power.data[r] = 1.0e6;
+    }
+    // The floor scaled by the correction for nave = 20 and 30 ranges, 1.3215551015061056
+    let expected = 132.15551015061055;
+    assert!((acf_cutoff_power(&rec, &settings) as f64 - expected).abs() < 1.0e-3);
+
+    // A floor below the SNR cutoff falls back to the search noise
+    for p in rec.lag_zero_power.data.iter_mut() {
+        *p = 0.5;
+    }
+    assert_eq!(acf_cutoff_power(&rec, &settings), 5.0);
+}
+
+#[test]
+fn test_two_parameter_line_fit() {
+    // y = 2x + 1 with unit sigmas, plus a zero-sigma point that must be ignored
+    let x = [0.0, 1.0, 2.0, 3.0, 4.0];
+    let y = [1.0, 3.0, 5.0, 7.0, 100.0];
+    let sigmas = [1.0, 1.0, 1.0, 1.0, 0.0];
+
+    let lsq = LeastSquares::new(1, 1);
+    let fitted = lsq.two_parameter_line_fit(&x, &y, &sigmas, FitType::Linear);
+    assert!(is_close!(fitted.slope, 2.0));
+    assert!(is_close!(fitted.intercept, 1.0));
+    assert!(is_close!(fitted.delta, 20.0));
+    assert!(is_close!(fitted.variance_intercept, 0.7));
+    assert!(is_close!(fitted.variance_slope, 0.2));
+    assert!(is_close!(fitted.covariance_intercept_slope, -0.3));
+    assert!(is_close!(
+        fitted.residual_intercept_slope,
+        -6.0 / 56.0_f64.sqrt()
+    ));
+    assert!(is_close!(fitted.delta_intercept, 0.7_f64.sqrt()));
+    assert!(is_close!(fitted.delta_slope, 0.2_f64.sqrt()));
+    assert!(fitted.chi_squared.abs() < 1e-12);
+}
+
+#[test]
+fn test_quadratic_line_fit() {
+    // Gaussian decay of the power, ln(p) = 2 - 0.5 t^2, so the fit is linear in t^2 = [0, 1, 4, 9]
+    let t = [0.0, 1.0, 2.0, 3.0, 4.0];
+    let ln_power = [2.0, 1.5, 0.0, -2.5, 100.0];
+    let sigmas = [1.0, 1.0, 1.0, 1.0, 0.0];
+
+    let lsq = LeastSquares::new(1, 1);
+    let fitted = lsq.two_parameter_line_fit(&t, &ln_power, &sigmas, FitType::Quadratic);
+    assert!(is_close!(fitted.slope, -0.5));
+    assert!(is_close!(fitted.intercept, 2.0));
+    assert!(is_close!(fitted.delta, 196.0));
+    assert!(is_close!(fitted.variance_intercept, 0.5));
+    assert!(is_close!(fitted.variance_slope, 1.0 / 49.0));
+    assert!(is_close!(fitted.covariance_intercept_slope, -1.0 / 14.0));
+    assert!(is_close!(
+        fitted.residual_intercept_slope,
+        -(0.5_f64.sqrt())
+    ));
+    // One sigma confidence for one degree of freedom, so the sigmas are the standard deviations
+    assert!(is_close!(fitted.delta_intercept, 0.5_f64.sqrt()));
+    assert!(is_close!(fitted.delta_slope, 1.0 / 7.0));
+    assert!(fitted.chi_squared.abs() < 1e-12);
+}
+
+#[test]
+fn test_one_parameter_line_fit() {
+    let x = [1.0, 2.0, 3.0];
+    let y = [3.0, 6.0, 9.0];
+    let sigmas = [1.0, 1.0, 1.0];
+
+    let lsq = LeastSquares::new(1, 1);
+    let fitted = lsq.one_parameter_line_fit(&x, &y, &sigmas);
+    assert!(is_close!(fitted.slope, 3.0));
+    assert!(is_close!(fitted.variance_slope, 1.0 / 14.0));
+    assert!(fitted.chi_squared.abs() < 1e-12);
+}
+
+fn scatter_cell(velocity: f64, spectral_width: f64) -> RadarCell {
+    RadarCell {
+        sct: true,
+        velocity,
+        spectral_width_lin: spectral_width,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_flag_groundscatter() {
+    let params = Fitacf3Settings::default().groundscatter_params();
+
+    let mut ionospheric = scatter_cell(400.0, 150.0);
+    flag_groundscatter(&mut ionospheric, &params);
+    assert!(!ionospheric.groundscatter);
+
+    let mut ground = scatter_cell(-5.0, 10.0);
+    flag_groundscatter(&mut ground, &params);
+    assert!(ground.groundscatter);
+
+    // Threshold is 30 - 45 * 30 / 90 = 15 m/s, with RST's 1 m/s of slack
+    let mut boundary = scatter_cell(16.0, 45.0);
+    flag_groundscatter(&mut boundary, &params);
+    assert!(!boundary.groundscatter);
+    boundary.velocity = 15.9;
+    flag_groundscatter(&mut boundary, &params);
+    assert!(boundary.groundscatter);
+
+    // Re-flagging a fitted scan with the fitting thresholds reproduces the fitted flags
+    let file = File::open("tests/test_files/test.fitacf").expect("Test file not found");
+    let fitacf = FitacfRecord::read_records(file).expect("Could not read records");
+    let scan = scan_from_fitacf(&fitacf).expect("Could not build scan");
+    let mut reflagged = scan.clone();
+    flag_scan_groundscatter(&mut reflagged, &params);
+    for (beam, reflagged_beam) in zip(scan.beams.iter(), reflagged.beams.iter()) {
+        for (cell, reflagged_cell) in zip(beam.cells.iter(), reflagged_beam.cells.iter()) {
+            assert_eq!(cell.groundscatter, reflagged_cell.groundscatter);
+        }
+    }
+}
+
+fn build_scan(cells: Vec<Vec<RadarCell>>) -> RadarScan {
+    let beams: Vec<RadarBeam> = cells
+        .into_iter()
+        .enumerate()
+        .map(|(i, cells)| RadarBeam {
+            scan: 0,
+            beam: i as i32,
+            beam_azimuth: 0.0,
+            time: 0.0,
+            control_program: 0,
+            intt_second: 3,
+            intt_microsecond: 0,
+            num_averages: 20,
+            first_range: 180,
+            range_sep: 45,
+            rx_rise_time: 0,
+            tx_freq: 10000,
+            noise: 1.0,
+            attenuation: 0,
+            channel: 0,
+            num_ranges: cells.len() as i32,
+            cells,
+        })
+        .collect();
+    RadarScan {
+        station_id: 0,
+        version_major: 3,
+        version_minor: 0,
+        start_time: 0.0,
+        end_time: 0.0,
+        num: beams.len(),
+        beams,
+    }
+}
+
+#[test]
+fn test_median_filter() {
+    let mut empty = scatter_cell(0.0, 0.0);
+    empty.sct = false;
+
+    // Uniform scatter with a single outlier in the centre
+    let mut cells = vec![vec![scatter_cell(100.0, 50.0); 5]; 3];
+    cells[1][2].velocity = 500.0;
+    let scan = build_scan(cells);
+    let filtered = median_filter(&[scan.clone(), scan.clone(), scan], FilterParams::default());
+    assert_eq!(filtered.len(), 3);
+    assert_eq!(filtered[1].beams[1].cells[2].velocity, 100.0);
+    assert_eq!(filtered[1].beams[1].valid_cells().count(), 5);
+
+    // A lone scatter cell does not survive
+    let mut cells = vec![vec![empty.clone(); 5]; 3];
+    cells[1][2] = scatter_cell(100.0, 50.0);
+    let scan = build_scan(cells);
+    let scans = [scan.clone(), scan.clone(), scan];
+    let filtered = median_filter(&scans, FilterParams::default());
+    let cell = &filtered[1].beams[1].cells[2];
+    assert!(!cell.sct);
+    assert_eq!(cell.velocity, 0.0);
+    assert_eq!(filtered[1].beams[1].valid_cells().count(), 0);
+
+    // The lone cell has weight 2 + 4 + 2 across the three scans, which must exceed the threshold
+    let filtered = median_filter(&scans, FilterParams { threshold: 8 });
+    assert!(!filtered[1].beams[1].cells[2].sct);
+    let filtered = median_filter(&scans, FilterParams { threshold: 7 });
+    assert!(filtered[1].beams[1].cells[2].sct);
+}
+
+#[test]
+fn test_lag_zero_power_error() {
+    assert!(is_close!(snr(10.0), 10.0));
+    assert!(is_close!(snr(0.0), 1.0));
+
+    // Error falls off as 1/sqrt(nave)
+    let error_1 = lag_zero_power_error(10.0, 1);
+    let error_4 = lag_zero_power_error(10.0, 4);
+    let error_100 = lag_zero_power_error(10.0, 100);
+    assert!(is_close!(error_1 / error_4, 2.0));
+    assert!(is_close!(error_1 / error_100, 10.0));
+
+    // Weaker signals have larger errors
+    assert!(lag_zero_power_error(0.0, 20) > lag_zero_power_error(10.0, 20));
+    assert!(is_close!(
+        lag_zero_power_error(0.0, 1),
+        20.0 / std::f64::consts::LN_10
+    ));
+
+    assert_eq!(lag_zero_power_error(10.0, 0), 0.0);
+}
+
+/// Shifts coordinates by a fixed offset, standing in for a real AACGM implementation
+struct OffsetCoords;
+
+impl MagneticCoord for OffsetCoords {
+    fn geo_to_magnetic(
+        &self,
+        lat: f64,
+        lon: f64,
+        _height: f64,
+        _year: i32,
+    ) -> Result<(f64, f64), BackscatterError> {
+        Ok((lat + 10.0, lon - 70.0))
+    }
+}
+
+#[test]
+fn test_geo_to_aacgm() {
+    let (mlat, mlon) = geo_to_aacgm(&OffsetCoords, 50.0, -100.0, 300.0, 2021)
+        .expect("Unable to convert coordinates");
+    assert_eq!(mlat, 60.0);
+    assert_eq!(mlon, -170.0);
+
+    assert!(geo_to_aacgm(&OffsetCoords, 91.0, 0.0, 300.0, 2021).is_err());
+    assert!(geo_to_aacgm(&OffsetCoords, 50.0, 0.0, -1.0, 2021).is_err());
+}
+
+#[test]
+fn test_range_passes_snr() {
+    let mut rec = load_test_rawacf().0.remove(0);
+
+    // 1 dB above a noise of 100 is a power of ~125.9
+    let noise = 100.0;
+    rec.lag_zero_power.data[0] = 126.0;
+    rec.lag_zero_power.data[1] = 125.0;
+    rec.lag_zero_power.data[2] = 0.0;
+    assert!(range_passes_snr(&rec, 0, noise, 1.0));
+    assert!(!range_passes_snr(&rec, 1, noise, 1.0));
+    assert!(!range_passes_snr(&rec, 2, noise, 1.0));
+    assert!(range_passes_snr(&rec, 1, noise, 0.5));
+
+    // Without a noise level, only the power is checked
+    assert!(range_passes_snr(&rec, 1, 0.0, 10.0));
+    assert!(!range_passes_snr(&rec, 2, 0.0, 10.0));
+
+    // Ranges outside the record never pass
+    assert!(!range_passes_snr(&rec, -1, noise, 1.0));
+    assert!(!range_passes_snr(&rec, rec.num_ranges as i32, noise, 1.0));
+}
+
+#[cfg(feature = "num-complex")]
+#[test]
+fn test_acf_complex() {
+    use backscatter_rs::fitting::fitacf3::complex::{acf_complex, xcf_complex};
+
+    let rec = load_test_rawacf().0.remove(0);
+    let num_lags = rec.num_lags as usize;
+
+    let acf = acf_complex(&rec, 1);
+    assert_eq!(acf.len(), num_lags);
+    assert_eq!(acf[0].re, rec.acfs.data[2 * num_lags] as f64);
+    assert_eq!(acf[0].im, rec.acfs.data[2 * num_lags + 1] as f64);
+
+    let xcfs = rec.xcfs.as_ref().expect("Test record has no xcfs");
+    let xcf = xcf_complex(&rec, 1).expect("Test record has no xcfs");
+    assert_eq!(xcf[num_lags - 1].im, xcfs.data[4 * num_lags - 1] as f64);
+}
+
+#[test]
+fn test_scan_times() {
+    let file = File::open("tests/test_files/test.fitacf").expect("Test file not found");
+    let mut fitacf = FitacfRecord::read_records(file).expect("Could not read records");
+
+    let first = record_epoch(&fitacf[0]).expect("Unable to get record time");
+    let last = record_epoch(&fitacf[fitacf.len() - 1]).expect("Unable to get record time");
+    assert!(is_close!(
+        first - first.floor(),
+        fitacf[0].microsecond as f64 * 1.0e-6,
+        abs_tol = 1e-6
+    ));
+
+    // Times are the extremes of the beams, regardless of record order
+    fitacf.reverse();
+    let scan = scan_from_fitacf(&fitacf).expect("Unable to create scan");
+    assert_eq!(scan.start_time, first);
+    assert_eq!(scan.end_time, last);
+}
+
+#[test]
+fn test_scans_channel_mode() {
+    let read_channel = |channel| {
+        let file = File::open("tests/test_files/test.fitacf").expect("Test file not found");
+        let mut records = FitacfRecord::read_records(file).expect("Could not read records");
+        for rec in records.iter_mut() {
+            rec.channel = channel;
+        }
+        records
+    };
+    let single =
+        scans_from_fitacf(&read_channel(1), ChannelMode::Separate).expect("Unable to create scans");
+
+    // Interleave the records of two channels sounding at the same times
+    let mut records = vec![];
+    for (a, b) in zip(read_channel(1), read_channel(2)) {
+        records.push(a);
+        records.push(b);
+    }
+
+    let separate =
+        scans_from_fitacf(&records, ChannelMode::Separate).expect("Unable to create scans");
+    assert_eq!(separate.len(), 2 * single.len());
+    assert!(separate
+        .windows(2)
+        .all(|s| s[0].start_time <= s[1].start_time));
+    // Both channels start their scans together, so their scans alternate
+    for (i, scan) in separate.iter().enumerate() {
+        let channel = if i % 2 == 0 { 1 } else { 2 };
+        assert!(scan.beams.iter().all(|b| b.channel == channel));
+        assert_eq!(scan.num, single[i / 2].num);
+    }
+
+    let merged = scans_from_fitacf(&records, ChannelMode::Merge).expect("Unable to create scans");
+    assert_eq!(merged.len(), single.len());
+    for (scan, single_scan) in zip(merged.iter(), single.iter()) {
+        assert_eq!(scan.num, 2 * single_scan.num);
+    }
+
+    let only = scans_from_fitacf(&records, ChannelMode::Only(2)).expect("Unable to create scans");
+    assert_eq!(only.len(), single.len());
+    assert!(only
+        .iter()
+        .flat_map(|s| s.beams.iter())
+        .all(|b| b.channel == 2));
+}
+
+#[test]
+fn test_scan_cells_indexed_by_range() {
+    let file = File::open("tests/test_files/test.fitacf").expect("Test file not found");
+    let records = FitacfRecord::read_records(file).expect("Could not read records");
+    let scan = scan_from_fitacf(&records).expect("Unable to create scan");
+    for (rec, beam) in zip(records.iter(), scan.beams.iter()) {
+        assert_eq!(beam.cells.len(), rec.num_ranges as usize);
+        for (i, &range_num) in rec.range_list.data.iter().enumerate() {
+            let cell = &beam.cells[range_num as usize];
+            assert!(cell.sct);
+            assert_eq!(cell.velocity, rec.velocity.data[i] as f64);
+        }
+        for (range_num, cell) in beam.cells.iter().enumerate() {
+            if !rec.range_list.data.iter().any(|&r| r as usize == range_num) {
+                assert_eq!(cell, &RadarCell::default());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_slant_range_and_virtual_height() {
+    assert!(is_close!(slant_range(180, 45, 100, 0), 165.0));
+    assert!(is_close!(slant_range(180, 45, 100, 10), 615.0));
+
+    let standard = VirtualHeight::default();
+    assert_eq!(standard.height(500.0), 115.0);
+    assert!(is_close!(standard.height(700.0), 207.5));
+    assert_eq!(standard.height(1000.0), 300.0);
+    assert_eq!(VirtualHeight::Standard(100.0).height(1000.0), 100.0);
+    // Near ranges are scaled down towards the radar regardless of the height
+    assert!(is_close!(standard.height(75.0), 57.5));
+    assert!(is_close!(VirtualHeight::Standard(100.0).height(75.0), 57.5));
+
+    let chisham = VirtualHeight::Chisham;
+    assert!(is_close!(chisham.height(57.5), 56.0));
+    assert!(is_close!(chisham.height(500.0), 135.244625));
+    assert!(is_close!(chisham.height(1000.0), 387.181));
+}
+
+#[test]
+fn test_cell_geolocation() {
+    let file = File::open("tests/test_files/test.fitacf").expect("Test file not found");
+    let records = FitacfRecord::read_records(file).expect("Could not read records");
+    let (rawacf, hdw) = load_test_rawacf();
+    let scan = scan_from_fitacf(&records).expect("Unable to create scan");
+    let beam = &scan.beams[0];
+    // The beam records no rise time, so the one of the hdw file is used
+    assert_eq!(beam.rx_rise_time, 0);
+    let rx_rise = hdw.rx_rise_time as i32;
+
+    // Great-circle distance in km from the radar to a cell
+    let ground_distance = |lat: f64, lon: f64| {
+        let (lat_1, lat_2) = ((hdw.latitude as f64).to_radians(), lat.to_radians());
+        let d_lat = lat_2 - lat_1;
+        let d_lon = (lon - hdw.longitude as f64).to_radians();
+        let a =
+            (d_lat / 2.0).sin().powi(2) + lat_1.cos() * lat_2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * 6371.0 * a.sqrt().asin()
+    };
+    for model in [VirtualHeight::default(), VirtualHeight::Chisham] {
+        let mut last_distance = 0.0;
+        for gate in [15, 30, 45, 60] {
+            let (lat, lon, height) =
+                cell_geolocation(beam, gate, &hdw, model).expect("Unable to locate cell");
+            let range = slant_range(beam.first_range, beam.range_sep, rx_rise, gate);
+            assert_eq!(height, model.height(range));
+            let distance = ground_distance(lat, lon);
+            assert!(distance > last_distance);
+            assert!(distance < range);
+            last_distance = distance;
+        }
+    }
+
+    // A radar on the equator looking due north, where the cell lies on the meridian at the
+    // geocentric latitude given by the law of cosines for the slant range and height
+    let mut equatorial = hdw;
+    equatorial.latitude = 0.0;
+    equatorial.longitude = 0.0;
+    equatorial.boresight = 0.0;
+    equatorial.boresight_shift = 0.0;
+    equatorial.beam_separation = 0.0;
+    equatorial.rx_rise_time = 0.0;
+    let mut beam = beam.clone();
+    beam.first_range = 180;
+    beam.range_sep = 45;
+    // Gate 15 is at 855 km, gate 30 at 1530 km
+    let (lat, lon, height) = cell_geolocation(&beam, 15, &equatorial, VirtualHeight::default())
+        .expect("Unable to locate cell");
+    assert_eq!(height, 300.0);
+    assert!(is_close!(lat, 7.0345, abs_tol = 0.01));
+    assert!(is_close!(lon, 0.0, abs_tol = 1e-9));
+    let (lat, lon, height) = cell_geolocation(&beam, 30, &equatorial, VirtualHeight::Chisham)
+        .expect("Unable to locate cell");
+    assert!(is_close!(height, 535.748, abs_tol = 1e-3));
+    assert!(is_close!(lat, 12.3934, abs_tol = 0.01));
+    assert!(is_close!(lon, 0.0, abs_tol = 1e-9));
+}
+
+#[test]
+fn test_merge_and_window_scans() {
+    let file = File::open("tests/test_files/test.fitacf").expect("Test file not found");
+    let records = FitacfRecord::read_records(file).expect("Could not read records");
+    let scans = scans_from_fitacf(&records, ChannelMode::Separate).expect("Unable to create scans");
+
+    let merged = merge_scans(scans).expect("Unable to merge scans");
+    assert_eq!(merged.num, records.len());
+    assert!(merged.beams.windows(2).all(|b| b[0].time <= b[1].time));
+
+    let windows = window_scans(&merged, 60.0).expect("Unable to window scan");
+    for scan in &windows {
+        assert!(scan.end_time - scan.start_time < 60.0);
+    }
+    let remerged = merge_scans(windows.clone()).expect("Unable to merge scans");
+    assert_eq!(remerged, merged);
+
+    let rewindowed = window_scans(&remerged, 60.0).expect("Unable to window scan");
+    assert_eq!(rewindowed, windows);
+
+    assert!(merge_scans(vec![]).is_err());
+    assert!(window_scans(&merged, 0.0).is_err());
+}
+
+#[test]
+fn test_sky_noise() {
+    // Mean of the 10 lowest powers
+    let pwr0: Vec<f64> = (1..=20).rev().map(|x| x as f64).collect();
+    assert_eq!(sky_noise(&pwr0), 5.5);
+
+    // Fewer than 10 ranges uses all of them
+    assert_eq!(sky_noise(&[4.0, 2.0, 6.0]), 4.0);
+    assert_eq!(sky_noise(&[3.0; 15]), 3.0);
+
+    // Clamped to a minimum of 1
+    assert_eq!(sky_noise(&[0.2, 0.5, 0.0]), 1.0);
+    assert_eq!(sky_noise(&[]), 1.0);
+}
+
+#[test]
+fn test_slope_to_velocity() {
+    // 100 rad/s at 10 MHz, to single precision
+    assert!(is_close!(
+        slope_to_velocity(100.0, 10000.0) as f64,
+        238.5672579618471,
+        rel_tol = 1e-6
+    ));
+    assert_eq!(
+        slope_to_velocity(-100.0, 10000.0),
+        -slope_to_velocity(100.0, 10000.0)
+    );
+    // Doubling the frequency halves the velocity for the same phase slope
+    assert_eq!(
+        slope_to_velocity(100.0, 20000.0),
+        slope_to_velocity(50.0, 10000.0)
+    );
+    assert_eq!(slope_to_velocity(0.0, 12000.0), 0.0);
+}
+
+#[test]
+fn test_decay_to_width() {
+    // 100 /s decay at 10 MHz is twice the velocity of a 100 rad/s phase slope
+    assert!(is_close!(
+        decay_to_width(-100.0, 10000.0) as f64,
+        477.1345159236942,
+        rel_tol = 1e-6
+    ));
+    assert_eq!(
+        decay_to_width(100.0, 10000.0),
+        decay_to_width(-100.0, 10000.0)
+    );
+
+    // 10000 /s^2 at 10 MHz
+    assert!(is_close!(
+        sigma_decay_to_width(-10000.0, 10000.0) as f64,
+        794.4810827495351,
+        rel_tol = 1e-6
+    ));
+}